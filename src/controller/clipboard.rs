@@ -0,0 +1,379 @@
+use std::{
+    collections::{HashMap, HashSet},
+    rc::Rc,
+};
+
+use crate::model::{
+    format::Format,
+    number::Number,
+    reference::Range,
+    sheet::{Cell, Sheet, COLUMNS, ROWS},
+    shift::Move,
+};
+
+/// What of the copied cells is pasted
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Contents {
+    /// Formulas, values and formats
+    #[default]
+    All,
+    /// The values the cells had when copied instead of their formulas
+    Values,
+    /// Formulas and values, without formats
+    Formulas,
+    Formats,
+}
+
+/// Combines the pasted numbers with the ones already in their place
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operation {
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct PasteOptions {
+    pub contents: Contents,
+    /// Leaves the cells in place of empty copied cells as they are
+    pub skip_blanks: bool,
+    /// Turns the rows of the copied cells into columns
+    pub transpose: bool,
+    /// Pastes the result of the operation on the number in place and the
+    /// copied one, empty cells counting as zero
+    pub operation: Option<Operation>,
+}
+
+/// Cells copied or cut from the sheet, waiting to be pasted
+#[derive(Debug)]
+pub struct Clipboard {
+    /// Where the cells were copied or cut from
+    pub source: Range,
+    /// The non-empty cells of the source by their offset from its top left
+    /// corner, as they were when copied
+    cells: HashMap<(usize, usize), Cell>,
+    /// The formats of the source other than general, of empty cells too
+    formats: HashMap<(usize, usize), Format>,
+    /// Cut cells are moved when pasted instead of copied
+    pub cut: bool,
+}
+
+impl Clipboard {
+    pub fn copy(sheet: &Sheet, source: Range) -> Self {
+        let offset = |p: (usize, usize)| (p.0 - source.from.0, p.1 - source.from.1);
+        let cells = sheet
+            .cells_in(source)
+            .map(|(p, c)| (offset(p), c.clone()))
+            .collect();
+        let formats = sheet
+            .formats_in(source)
+            .map(|(p, f)| (offset(p), f))
+            .collect();
+        Clipboard {
+            source,
+            cells,
+            formats,
+            cut: false,
+        }
+    }
+
+    pub fn cut(sheet: &Sheet, source: Range) -> Self {
+        Clipboard {
+            cut: true,
+            ..Clipboard::copy(sheet, source)
+        }
+    }
+
+    /// The copied values as shown in the sheet, separated by tabs and lines,
+    /// for other applications. Ends after the last non-empty row.
+    pub fn text(&self, sheet: &Sheet) -> String {
+        let Some(height) = self.cells.keys().map(|(_, y)| y + 1).max() else {
+            return String::new();
+        };
+        let mut rows = vec![vec![String::new(); self.source.width()]; height];
+        for (&(x, y), cell) in &self.cells {
+            let format = self.formats.get(&(x, y)).copied().unwrap_or_default();
+            format
+                .write_cell(cell, sheet.settings.rounding, &mut rows[y][x])
+                .unwrap();
+        }
+
+        let mut text = String::new();
+        for row in rows {
+            let end = row.iter().rposition(|s| !s.is_empty()).map_or(0, |i| i + 1);
+            text.push_str(&row[..end].join("\t"));
+            text.push('\n');
+        }
+        text
+    }
+
+    /// Pastes with the top left corner at `at`. Copied formulas keep their
+    /// relative references pointing at cells at the same distance, cut cells
+    /// are moved together with every reference to them and can only be
+    /// pasted as they are. Fails if the cells would not fit on the sheet.
+    pub fn paste(&self, sheet: &mut Sheet, at: (usize, usize), options: PasteOptions) -> bool {
+        if self.cut {
+            return options == PasteOptions::default()
+                && sheet.move_cells(Move {
+                    source: self.source,
+                    to: at,
+                });
+        }
+
+        let place = |offset: (usize, usize)| match options.transpose {
+            false => (at.0 + offset.0, at.1 + offset.1),
+            true => (at.0 + offset.1, at.1 + offset.0),
+        };
+        let target = Range::new(
+            at,
+            place((self.source.width() - 1, self.source.height() - 1)),
+        );
+        if target.to.0 >= COLUMNS || target.to.1 >= ROWS {
+            return false;
+        }
+
+        // Empty copied cells replace the cells in their place unless skipped
+        let mut cells = vec![];
+        let with_cells = options.contents != Contents::Formats && options.operation.is_none();
+        if with_cells && !options.skip_blanks {
+            let copied: HashSet<(usize, usize)> = self.cells.keys().map(|o| place(*o)).collect();
+            cells.extend(
+                sheet
+                    .cells_in(target)
+                    .map(|(p, _)| p)
+                    .filter(|p| !copied.contains(p))
+                    .map(|p| (p, Cell::None)),
+            );
+        }
+
+        // Formats of empty copied cells are pasted as well unless skipped,
+        // replacing the formats in their place
+        let format =
+            |offset: &(usize, usize)| self.formats.get(offset).copied().unwrap_or_default();
+        match options.contents {
+            Contents::All | Contents::Formats if options.skip_blanks => {
+                sheet.set_formats(self.cells.keys().map(|o| (place(*o), format(o))).collect());
+            }
+            Contents::All | Contents::Formats => {
+                let mut formats: Vec<((usize, usize), Format)> = sheet
+                    .formats_in(target)
+                    .map(|(p, _)| (p, Format::General))
+                    .collect();
+                formats.extend(self.formats.iter().map(|(o, f)| (place(*o), *f)));
+                sheet.set_formats(formats);
+            }
+            _ => (),
+        }
+
+        for (offset, cell) in &self.cells {
+            let to = place(*offset);
+            let cell = match (options.contents, cell) {
+                (Contents::Formats, _) => continue,
+                (Contents::Values, cell) => cell.computed(),
+                (_, Cell::Expression(ex, r)) => {
+                    let ex = match options.transpose {
+                        false => ex.copied(to, &mut sheet.formulas),
+                        true => ex.transposed(to, &mut sheet.formulas),
+                    };
+                    Cell::Expression(Rc::new(ex), r.clone())
+                }
+                (_, cell) => cell.clone(),
+            };
+            let cell = match options.operation {
+                Some(op) => match combine(op, sheet.get(to), &cell, sheet.settings.big_int) {
+                    Some(n) => Cell::Val(n),
+                    None => continue,
+                },
+                None => cell,
+            };
+            cells.push((to, cell));
+        }
+        sheet.insert_cells(cells);
+        true
+    }
+}
+
+/// The number in place combined with the pasted one, `None` if either is not
+/// a number
+fn combine(op: Operation, current: Option<&Cell>, pasted: &Cell, big: bool) -> Option<Number> {
+    let number = |c: &Cell| match c {
+        Cell::None => Some(Number::Int(0)),
+        c => c.val(),
+    };
+    let (a, b) = (number(current?)?, number(pasted)?);
+    match op {
+        Operation::Add => a.add(&b, big),
+        Operation::Subtract => a.sub(&b, big),
+        Operation::Multiply => a.mul(&b, big),
+        Operation::Divide => a.div(&b, big),
+    }
+    .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sheet() -> Sheet {
+        let mut sheet = Sheet::new();
+        sheet.enter((0, 0), "1");
+        sheet.enter((0, 1), "2");
+        sheet.enter((1, 0), "=A1*10");
+        sheet.enter((1, 1), "=A2+$A$1");
+        sheet
+    }
+
+    #[test]
+    fn copies_keep_relative_references() {
+        let mut sheet = sheet();
+        let clipboard = Clipboard::copy(&sheet, Range::new((1, 0), (1, 1)));
+        assert!(clipboard.paste(&mut sheet, (2, 1), PasteOptions::default()));
+        assert_eq!(sheet.entry((2, 1)), "=B2*10");
+        assert_eq!(sheet.entry((2, 2)), "=B3+$A$1");
+        assert_eq!(sheet.shown((2, 1)), "30");
+        // The copy is left as it was
+        assert_eq!(sheet.entry((1, 0)), "=A1*10");
+    }
+
+    #[test]
+    fn empty_copied_cells_replace_the_ones_in_place() {
+        let mut sheet = sheet();
+        sheet.enter((4, 1), "x");
+        let clipboard = Clipboard::copy(&sheet, Range::new((2, 0), (3, 1)));
+        assert!(clipboard.paste(&mut sheet, (3, 0), PasteOptions::default()));
+        assert!(matches!(sheet.get((4, 1)), Some(Cell::None)));
+    }
+
+    #[test]
+    fn cuts_move_the_cells_and_their_references() {
+        let mut sheet = sheet();
+        let clipboard = Clipboard::cut(&sheet, Range::new((0, 0), (0, 1)));
+        let transpose = PasteOptions {
+            transpose: true,
+            ..PasteOptions::default()
+        };
+        assert!(!clipboard.paste(&mut sheet, (5, 5), transpose));
+        assert!(clipboard.paste(&mut sheet, (5, 5), PasteOptions::default()));
+        assert_eq!(sheet.entry((1, 0)), "=F6*10");
+        assert_eq!(sheet.entry((1, 1)), "=F7+$F$6");
+        assert!(matches!(sheet.get((0, 0)), Some(Cell::None)));
+        assert_eq!(sheet.shown((1, 1)), "3");
+    }
+
+    #[test]
+    fn pastes_have_to_fit() {
+        let mut sheet = sheet();
+        let clipboard = Clipboard::copy(&sheet, Range::new((0, 0), (1, 1)));
+        assert!(!clipboard.paste(&mut sheet, (COLUMNS - 1, 0), PasteOptions::default()));
+        assert!(!clipboard.paste(&mut sheet, (0, ROWS - 1), PasteOptions::default()));
+        assert!(clipboard.paste(&mut sheet, (COLUMNS - 2, ROWS - 2), PasteOptions::default()));
+    }
+
+    #[test]
+    fn text_as_shown_by_tabs_and_lines() {
+        let mut sheet = sheet();
+        sheet.set_format(Format::Currency, (0, 1));
+        sheet.enter((0, 3), "last");
+        let clipboard = Clipboard::copy(&sheet, Range::new((0, 0), (2, 5)));
+        assert_eq!(clipboard.text(&sheet), "1\t10\n$2.00\t3\n\nlast\n");
+        let empty = Clipboard::copy(&sheet, Range::new((7, 7), (8, 8)));
+        assert_eq!(empty.text(&sheet), "");
+    }
+
+    fn special(contents: Contents) -> PasteOptions {
+        PasteOptions {
+            contents,
+            ..PasteOptions::default()
+        }
+    }
+
+    #[test]
+    fn values_formulas_and_formats() {
+        let mut sheet = sheet();
+        sheet.set_format(Format::Percent, (1, 0));
+        let clipboard = Clipboard::copy(&sheet, Range::new((1, 0), (1, 1)));
+
+        assert!(clipboard.paste(&mut sheet, (3, 0), special(Contents::Values)));
+        assert_eq!(sheet.entry((3, 0)), "10");
+        assert_eq!(sheet.format((3, 0)), Format::General);
+
+        assert!(clipboard.paste(&mut sheet, (4, 0), special(Contents::Formulas)));
+        assert_eq!(sheet.entry((4, 0)), "=D1*10");
+        assert_eq!(sheet.format((4, 0)), Format::General);
+
+        sheet.enter((5, 0), "0.5");
+        assert!(clipboard.paste(&mut sheet, (5, 0), special(Contents::Formats)));
+        assert_eq!(sheet.shown((5, 0)), "50%");
+    }
+
+    #[test]
+    fn formats_of_empty_cells_are_pasted() {
+        let mut sheet = sheet();
+        sheet.set_format(Format::Currency, (2, 0));
+        sheet.set_format(Format::Percent, (3, 1));
+        let clipboard = Clipboard::copy(&sheet, Range::new((2, 0), (2, 1)));
+        assert!(clipboard.paste(&mut sheet, (3, 0), PasteOptions::default()));
+        assert_eq!(sheet.format((3, 0)), Format::Currency);
+        assert_eq!(sheet.format((3, 1)), Format::General);
+    }
+
+    #[test]
+    fn skipping_blanks() {
+        let mut sheet = sheet();
+        sheet.enter((4, 1), "x");
+        sheet.set_format(Format::Percent, (4, 1));
+        let clipboard = Clipboard::copy(&sheet, Range::new((0, 0), (0, 1)));
+        let options = PasteOptions {
+            skip_blanks: true,
+            ..PasteOptions::default()
+        };
+        let blank = Clipboard::copy(&sheet, Range::new((3, 0), (3, 1)));
+        assert!(blank.paste(&mut sheet, (4, 0), options));
+        assert_eq!(sheet.entry((4, 1)), "x");
+        assert_eq!(sheet.format((4, 1)), Format::Percent);
+        assert!(clipboard.paste(&mut sheet, (4, 0), options));
+        assert_eq!(sheet.entry((4, 1)), "2");
+    }
+
+    #[test]
+    fn transposing() {
+        let mut sheet = sheet();
+        let clipboard = Clipboard::copy(&sheet, Range::new((0, 0), (1, 1)));
+        let options = PasteOptions {
+            transpose: true,
+            ..PasteOptions::default()
+        };
+        assert!(clipboard.paste(&mut sheet, (3, 3), options));
+        assert_eq!(sheet.entry((4, 3)), "2");
+        assert_eq!(sheet.entry((3, 4)), "=D4*10");
+        assert_eq!(sheet.entry((4, 4)), "=E4+$A$1");
+        assert_eq!(sheet.shown((4, 4)), "3");
+        assert!(!clipboard.paste(&mut sheet, (COLUMNS - 1, 0), options));
+    }
+
+    #[test]
+    fn combining_with_the_numbers_in_place() {
+        let mut sheet = sheet();
+        sheet.enter((3, 0), "5");
+        sheet.enter((3, 1), "text");
+        let clipboard = Clipboard::copy(&sheet, Range::new((0, 0), (0, 2)));
+        let with = |operation| PasteOptions {
+            operation: Some(operation),
+            ..PasteOptions::default()
+        };
+        assert!(clipboard.paste(&mut sheet, (3, 0), with(Operation::Add)));
+        assert_eq!(sheet.entry((3, 0)), "6");
+        assert_eq!(sheet.entry((3, 1)), "text");
+        assert!(clipboard.paste(&mut sheet, (3, 0), with(Operation::Multiply)));
+        assert_eq!(sheet.entry((3, 0)), "6");
+        assert!(clipboard.paste(&mut sheet, (3, 0), with(Operation::Subtract)));
+        assert_eq!(sheet.entry((3, 0)), "5");
+        // Empty cells count as zero, dividing by one leaves the cell alone
+        assert!(clipboard.paste(&mut sheet, (6, 0), with(Operation::Divide)));
+        assert_eq!(sheet.entry((6, 0)), "0");
+        assert!(clipboard.paste(&mut sheet, (0, 0), with(Operation::Divide)));
+        assert_eq!(sheet.entry((0, 1)), "1");
+        assert!(matches!(sheet.get((0, 2)), Some(Cell::None)));
+    }
+}
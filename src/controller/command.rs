@@ -0,0 +1,407 @@
+use rust_decimal::Decimal;
+
+use super::clipboard::{Contents, Operation, PasteOptions};
+use super::links::Direction;
+
+use crate::model::{
+    fill::FillDirection,
+    filter::{Comparison, Criterion},
+    format::Format,
+    number::Number,
+    reference::{column_index, parse_cell, parse_range, Range},
+    settings::Rounding,
+    sheet::{COLUMNS, ROWS},
+    shift::Axis,
+    solver::{Objective, Variable},
+    sort::SortKey,
+    value::Value,
+};
+
+/// Narrowest column, one character is kept free between columns
+const MIN_WIDTH: u16 = 2;
+const MAX_WIDTH: u16 = 100;
+
+/// Commands typed into the entry line after a leading `:`
+#[derive(Debug, PartialEq)]
+pub enum Command {
+    Set(Setting),
+    /// Changes the format of the selected cell
+    Format(Format),
+    /// `width 12` makes the selected column 12 characters wide
+    Width(u16),
+    /// `insert row 3` puts 3 empty rows in front of the selected one,
+    /// `insert col` one column
+    Insert(Axis, usize),
+    /// `delete row 3` removes the selected row and the 2 below it
+    Delete(Axis, usize),
+    /// `goalseek B5 100 A1` changes A1 until B5 gives 100
+    GoalSeek {
+        formula: (usize, usize),
+        target: f64,
+        input: (usize, usize),
+    },
+    /// `solve C1 max A1=0..10 B1=..5` changes A1 and B1 within their bounds
+    /// to make C1 as large as possible, `min` or a number are also accepted
+    Solve {
+        objective: (usize, usize),
+        goal: Objective,
+        variables: Vec<Variable>,
+    },
+    /// `datatable B2:E10 row=A1 col=A2` fills the range with results for
+    /// the inputs along its first row and down its first column
+    DataTable {
+        range: Range,
+        row_input: Option<(usize, usize)>,
+        column_input: Option<(usize, usize)>,
+    },
+    Scenario(ScenarioCommand),
+    /// `copy B2:C5` copies the range, the selected cell without one
+    Copy(Option<Range>),
+    /// `cut B2:C5` moves the range on the next paste
+    Cut(Option<Range>),
+    /// Pastes the copied or cut cells at the selected one, `paste values
+    /// transpose` or any other combination of [PasteOptions] pastes only
+    /// part of them or changes how
+    Paste(PasteOptions),
+    /// `fill down A1:C10` extends the cells at the top of each column over
+    /// the rest of it, continuing the series they start
+    Fill(FillDirection, Range),
+    /// `sort A1:D20 C desc A` sorts the rows of the range by column C from
+    /// the largest value and then by column A, by the first column without
+    /// keys. `header` or `noheader` overrides telling whether the first row
+    /// is a header.
+    Sort {
+        range: Range,
+        keys: Vec<SortKey>,
+        header: Option<bool>,
+    },
+    Filter(FilterCommand),
+    Undo,
+    Redo,
+    /// Steps through the evaluation of the selected formula
+    Debug,
+    /// Highlights the cells linked to the selected one, one level further
+    /// on every repeat
+    Trace(Direction),
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ScenarioCommand {
+    /// `scenario add best A1=5 B1` keeps 5 for A1 and the current value of B1
+    Add {
+        name: String,
+        cells: Vec<((usize, usize), Option<Value>)>,
+    },
+    Delete(String),
+    Show(String),
+    Revert,
+    /// `scenario summary C10 D10` writes the scenarios with the results of
+    /// C10 and D10 under each at the selected cell
+    Summary(Vec<(usize, usize)>),
+}
+
+#[derive(Debug, PartialEq)]
+pub enum FilterCommand {
+    /// `filter A1:D20` filters the rows below the header A1:D1
+    On(Range),
+    Off,
+    /// `filter B values Rome,Paris`, `filter B >=10`, `filter B contains
+    /// road`, `filter B top 5` or `filter B bottom 5` hides the rows not
+    /// matching in column B
+    Set(usize, Criterion),
+    /// `filter B clear` shows the rows hidden for column B again
+    Clear(usize),
+    /// Applies the criteria again to the values as they are now
+    Apply,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Setting {
+    BigInt(bool),
+    Rounding(Rounding),
+    Seed(u64),
+    Iterate(bool),
+    MaxIterations(u32),
+    Tolerance(Decimal),
+    /// Number of steps that can be undone
+    History(usize),
+}
+
+/// Parses the text following the `:` of a command
+pub fn parse(s: &str) -> Option<Command> {
+    let mut words = s.split_whitespace();
+
+    match words.next()? {
+        "set" => {
+            let setting = match words.next()? {
+                "bigint" => Setting::BigInt(true),
+                "nobigint" => Setting::BigInt(false),
+                "bankers" => Setting::Rounding(Rounding::HalfEven),
+                "nobankers" => Setting::Rounding(Rounding::HalfAwayFromZero),
+                "seed" => Setting::Seed(words.next()?.parse().ok()?),
+                "iterate" => Setting::Iterate(true),
+                "noiterate" => Setting::Iterate(false),
+                "iterations" => Setting::MaxIterations(words.next()?.parse().ok()?),
+                "tolerance" => Setting::Tolerance(words.next()?.parse().ok()?),
+                "history" => Setting::History(words.next()?.parse().ok()?),
+                _ => return None,
+            };
+            Some(Command::Set(setting))
+        }
+        "format" => Format::from_name(words.next()?).map(Command::Format),
+        "goalseek" => Some(Command::GoalSeek {
+            formula: parse_cell(words.next()?)?,
+            target: words.next()?.parse().ok()?,
+            input: parse_cell(words.next()?)?,
+        }),
+        "solve" => {
+            let objective = parse_cell(words.next()?)?;
+            let goal = match words.next()? {
+                "min" => Objective::Min,
+                "max" => Objective::Max,
+                target => Objective::Target(target.parse().ok()?),
+            };
+            let variables = words.map(variable).collect::<Option<Vec<_>>>()?;
+            if variables.is_empty() {
+                return None;
+            }
+            Some(Command::Solve {
+                objective,
+                goal,
+                variables,
+            })
+        }
+        "width" => {
+            let width = words.next()?.parse().ok()?;
+            (MIN_WIDTH..=MAX_WIDTH)
+                .contains(&width)
+                .then_some(Command::Width(width))
+        }
+        cmd @ ("insert" | "delete") => {
+            let axis = match words.next()? {
+                "row" | "rows" => Axis::Row,
+                "col" | "cols" | "column" | "columns" => Axis::Column,
+                _ => return None,
+            };
+            let most = match axis {
+                Axis::Row => ROWS,
+                Axis::Column => COLUMNS,
+            };
+            let count = match words.next() {
+                Some(n) => n.parse().ok().filter(|n| (1..=most).contains(n))?,
+                None => 1,
+            };
+            Some(match cmd {
+                "insert" => Command::Insert(axis, count),
+                _ => Command::Delete(axis, count),
+            })
+        }
+        cmd @ ("copy" | "cut") => {
+            let range = match words.next() {
+                Some(r) => Some(parse_range(r)?),
+                None => None,
+            };
+            Some(match cmd {
+                "copy" => Command::Copy(range),
+                _ => Command::Cut(range),
+            })
+        }
+        "paste" => {
+            let mut options = PasteOptions::default();
+            for word in words {
+                match word {
+                    "values" => options.contents = Contents::Values,
+                    "formulas" => options.contents = Contents::Formulas,
+                    "formats" => options.contents = Contents::Formats,
+                    "skipblanks" => options.skip_blanks = true,
+                    "transpose" => options.transpose = true,
+                    "add" => options.operation = Some(Operation::Add),
+                    "subtract" => options.operation = Some(Operation::Subtract),
+                    "multiply" => options.operation = Some(Operation::Multiply),
+                    "divide" => options.operation = Some(Operation::Divide),
+                    _ => return None,
+                }
+            }
+            Some(Command::Paste(options))
+        }
+        "fill" => {
+            let direction = match words.next()? {
+                "down" => FillDirection::Down,
+                "right" => FillDirection::Right,
+                _ => return None,
+            };
+            Some(Command::Fill(direction, parse_range(words.next()?)?))
+        }
+        "sort" => {
+            let range = parse_range(words.next()?)?;
+            let (mut keys, mut header): (Vec<SortKey>, _) = (vec![], None);
+            for word in words {
+                match word {
+                    "asc" | "desc" => keys.last_mut()?.descending = word == "desc",
+                    "header" => header = Some(true),
+                    "noheader" => header = Some(false),
+                    column => keys.push(SortKey {
+                        column: column_index(column)?,
+                        descending: false,
+                    }),
+                }
+            }
+            if keys.is_empty() {
+                keys.push(SortKey {
+                    column: range.from.0,
+                    descending: false,
+                });
+            }
+            Some(Command::Sort {
+                range,
+                keys,
+                header,
+            })
+        }
+        "filter" => {
+            let filter = match words.next()? {
+                "off" => FilterCommand::Off,
+                "apply" => FilterCommand::Apply,
+                word => match column_index(word) {
+                    Some(x) => {
+                        let rest = words.collect::<Vec<_>>().join(" ");
+                        match rest.as_str() {
+                            "clear" => FilterCommand::Clear(x),
+                            rest => FilterCommand::Set(x, criterion(rest)?),
+                        }
+                    }
+                    None => FilterCommand::On(parse_range(word)?),
+                },
+            };
+            Some(Command::Filter(filter))
+        }
+        "undo" => Some(Command::Undo),
+        "redo" => Some(Command::Redo),
+        "debug" => Some(Command::Debug),
+        "precedents" => Some(Command::Trace(Direction::Precedents)),
+        "dependents" => Some(Command::Trace(Direction::Dependents)),
+        "datatable" => {
+            let range = parse_range(words.next()?)?;
+            let (mut row_input, mut column_input) = (None, None);
+            for w in words {
+                match w.split_once('=')? {
+                    ("row", cell) => row_input = Some(parse_cell(cell)?),
+                    ("col", cell) => column_input = Some(parse_cell(cell)?),
+                    _ => return None,
+                }
+            }
+            Some(Command::DataTable {
+                range,
+                row_input,
+                column_input,
+            })
+        }
+        "scenario" => {
+            let scenario = match words.next()? {
+                "add" => ScenarioCommand::Add {
+                    name: words.next()?.to_owned(),
+                    cells: words.map(scenario_cell).collect::<Option<_>>()?,
+                },
+                "delete" => ScenarioCommand::Delete(words.next()?.to_owned()),
+                "show" => ScenarioCommand::Show(words.next()?.to_owned()),
+                "revert" => ScenarioCommand::Revert,
+                "summary" => {
+                    ScenarioCommand::Summary(words.map(parse_cell).collect::<Option<_>>()?)
+                }
+                _ => return None,
+            };
+            Some(Command::Scenario(scenario))
+        }
+        _ => None,
+    }
+}
+
+/// Parses what a filtered column is to match, the text after its column in
+/// `filter B >=10`
+fn criterion(s: &str) -> Option<Criterion> {
+    let (word, rest) = s.split_once(' ').unwrap_or((s, ""));
+    let count = || rest.trim().parse().ok().filter(|n| *n > 0);
+    match word {
+        "values" => Some(Criterion::Values(
+            rest.split(',').map(|v| v.trim().to_owned()).collect(),
+        )),
+        "contains" if !rest.is_empty() => Some(Criterion::Contains(rest.to_owned())),
+        "top" => Some(Criterion::Top {
+            count: count()?,
+            bottom: false,
+        }),
+        "bottom" => Some(Criterion::Top {
+            count: count()?,
+            bottom: true,
+        }),
+        _ => {
+            let (op, number) = [
+                (">=", Comparison::GreaterOrEqual),
+                ("<=", Comparison::LessOrEqual),
+                ("<>", Comparison::NotEqual),
+                (">", Comparison::Greater),
+                ("<", Comparison::Less),
+                ("=", Comparison::Equal),
+            ]
+            .into_iter()
+            .find_map(|(prefix, op)| Some((op, s.strip_prefix(prefix)?)))?;
+            Some(Criterion::Compare(op, number.trim().parse().ok()?))
+        }
+    }
+}
+
+/// Parses a cell of a scenario, `A1` or with the value to give it `A1=5`
+fn scenario_cell(s: &str) -> Option<((usize, usize), Option<Value>)> {
+    match s.split_once('=') {
+        Some((cell, value)) => {
+            let value = value
+                .parse::<Number>()
+                .map_or_else(|_| Value::Text(value.to_owned()), Value::Number);
+            Some((parse_cell(cell)?, Some(value)))
+        }
+        None => Some((parse_cell(s)?, None)),
+    }
+}
+
+/// Parses a solver variable, `A1`, `A1=0..10`, `A1=0..` or `A1=..10`
+fn variable(s: &str) -> Option<Variable> {
+    let (cell, bounds) = s.split_once('=').unwrap_or((s, ".."));
+    let (low, high) = bounds.split_once("..")?;
+    let bound = |b: &str, none: f64| match b {
+        "" => Some(none),
+        b => b.parse().ok(),
+    };
+    let (low, high) = (bound(low, f64::NEG_INFINITY)?, bound(high, f64::INFINITY)?);
+    (low <= high).then_some(Variable {
+        pos: parse_cell(cell)?,
+        low,
+        high,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn inserting_and_deleting() {
+        assert_eq!(parse("insert row"), Some(Command::Insert(Axis::Row, 1)));
+        assert_eq!(
+            parse("insert cols 3"),
+            Some(Command::Insert(Axis::Column, 3))
+        );
+        assert_eq!(parse("delete rows 2"), Some(Command::Delete(Axis::Row, 2)));
+        assert_eq!(parse("insert cell"), None);
+    }
+
+    #[test]
+    fn counts_stay_within_the_sheet() {
+        let rows = format!("delete rows {}", ROWS);
+        assert_eq!(parse(&rows), Some(Command::Delete(Axis::Row, ROWS)));
+        assert_eq!(parse(&format!("insert rows {}", ROWS + 1)), None);
+        assert_eq!(parse(&format!("insert cols {}", COLUMNS + 1)), None);
+        assert_eq!(parse(&format!("delete rows {}", usize::MAX)), None);
+        assert_eq!(parse("insert rows 0"), None);
+        assert_eq!(parse("insert rows -1"), None);
+    }
+}
@@ -0,0 +1,196 @@
+use std::collections::{HashSet, VecDeque};
+
+use crate::model::sheet::{Change, Sheet};
+
+/// Steps kept for undo unless set otherwise
+pub const DEFAULT_DEPTH: usize = 100;
+
+/// Undo and redo stacks, every step holds the changes of one edit or command
+/// so a paste or a data table is undone at once
+#[derive(Debug)]
+pub struct History {
+    undo: VecDeque<Vec<Change>>,
+    redo: Vec<Vec<Change>>,
+    /// Most steps kept, the oldest ones are forgotten first
+    depth: usize,
+}
+
+impl History {
+    pub fn new(depth: usize) -> Self {
+        History {
+            undo: VecDeque::new(),
+            redo: vec![],
+            depth,
+        }
+    }
+
+    pub fn set_depth(&mut self, depth: usize) {
+        self.depth = depth;
+        self.undo.truncate(depth);
+        self.redo.truncate(depth);
+    }
+
+    /// Adds the changes of a new step, which can no longer be redone past
+    pub fn record(&mut self, changes: Vec<Change>) {
+        if changes.is_empty() {
+            return;
+        }
+        self.redo.clear();
+        self.push_undo(changes);
+    }
+
+    /// Undoes the last step, returns false if there is none
+    pub fn undo(&mut self, sheet: &mut Sheet) -> bool {
+        let Some(step) = self.undo.pop_front() else {
+            return false;
+        };
+        self.redo.push(replay(step, sheet));
+        true
+    }
+
+    /// Redoes the last undone step, returns false if there is none
+    pub fn redo(&mut self, sheet: &mut Sheet) -> bool {
+        let Some(step) = self.redo.pop() else {
+            return false;
+        };
+        let step = replay(step, sheet);
+        self.push_undo(step);
+        true
+    }
+
+    fn push_undo(&mut self, changes: Vec<Change>) {
+        self.undo.push_front(coalesce(changes));
+        self.undo.truncate(self.depth);
+    }
+}
+
+/// Undoes the `changes` of a step, giving the changes that undo that again
+fn replay(changes: Vec<Change>, sheet: &mut Sheet) -> Vec<Change> {
+    for change in changes.into_iter().rev() {
+        sheet.apply(change);
+    }
+    coalesce(sheet.take_changes())
+}
+
+/// Keeps only the first change of a cell between shifts, undoing the later
+/// ones is overwritten by it anyway
+fn coalesce(changes: Vec<Change>) -> Vec<Change> {
+    let mut seen = HashSet::new();
+    let mut kept = vec![];
+    for change in changes {
+        match &change {
+            Change::Cell(pos, _) if !seen.insert(*pos) => continue,
+            Change::Shift(_) => seen.clear(),
+            _ => (),
+        }
+        kept.push(change);
+    }
+    kept
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::model::{
+        filter::AutoFilter,
+        number::Number,
+        reference::Range,
+        settings::{Iteration, Rounding},
+        sheet::Cell,
+        shift::{Axis, Shift},
+        value::Value,
+        whatif,
+    };
+
+    use super::*;
+
+    /// Makes an edit with `edit` and records it as one step
+    fn step(history: &mut History, sheet: &mut Sheet, edit: impl FnOnce(&mut Sheet)) {
+        edit(sheet);
+        history.record(sheet.take_changes());
+    }
+
+    #[test]
+    fn steps_are_undone_and_redone_whole() {
+        let (mut history, mut sheet) = (History::new(DEFAULT_DEPTH), Sheet::new());
+        step(&mut history, &mut sheet, |s| s.enter((0, 0), "1"));
+        step(&mut history, &mut sheet, |s| {
+            s.insert_cells(vec![
+                ((0, 0), Cell::Val(Number::Int(2))),
+                ((0, 1), Cell::Val(Number::Int(3))),
+            ]);
+            s.enter((0, 0), "4");
+        });
+
+        assert!(history.undo(&mut sheet));
+        assert_eq!(sheet.shown((0, 0)), "1");
+        assert!(matches!(sheet.get((0, 1)), Some(Cell::None)));
+        assert!(history.redo(&mut sheet));
+        assert_eq!(sheet.shown((0, 0)), "4");
+        assert_eq!(sheet.shown((0, 1)), "3");
+        assert!(!history.redo(&mut sheet));
+
+        assert!(history.undo(&mut sheet));
+        assert!(history.undo(&mut sheet));
+        assert!(!history.undo(&mut sheet));
+        assert!(sheet.cells().next().is_none());
+
+        // A new step can no longer be redone past
+        step(&mut history, &mut sheet, |s| s.enter((1, 0), "5"));
+        assert!(!history.redo(&mut sheet));
+    }
+
+    #[test]
+    fn only_the_most_recent_steps_are_kept() {
+        let (mut history, mut sheet) = (History::new(2), Sheet::new());
+        for n in ["1", "2", "3"] {
+            step(&mut history, &mut sheet, |s| s.enter((0, 0), n));
+        }
+        assert!(history.undo(&mut sheet));
+        assert!(history.undo(&mut sheet));
+        assert!(!history.undo(&mut sheet));
+        assert_eq!(sheet.shown((0, 0)), "1");
+    }
+
+    #[test]
+    fn settings_are_not_recorded() {
+        let mut sheet = Sheet::new();
+        sheet.enter((0, 0), "=ROUND(2.5)");
+        sheet.take_changes();
+        sheet.set_big_int(true);
+        sheet.set_rounding(Rounding::HalfEven);
+        sheet.set_iteration(Iteration::default());
+        sheet.set_seed(1);
+        assert_eq!(sheet.shown((0, 0)), "2");
+        assert!(sheet.take_changes().is_empty());
+    }
+
+    #[test]
+    fn undoing_a_shift_restores_scenarios_and_the_filter() {
+        let (mut history, mut sheet) = (History::new(DEFAULT_DEPTH), Sheet::new());
+        sheet.enter((0, 3), "1");
+        sheet.enter((1, 0), "=A4*2");
+        let ten = Some(Value::Number(Number::Int(10)));
+        whatif::add_scenario(&mut sheet, "high", vec![((0, 3), ten)]).unwrap();
+        sheet.set_filter(Some(AutoFilter::new(Range::new((0, 3), (0, 5)))));
+        sheet.take_changes();
+
+        step(&mut history, &mut sheet, |s| {
+            s.shift(Shift::delete(Axis::Row, 3, 1));
+        });
+        assert_eq!(sheet.filter(), None);
+        assert!(whatif::show_scenario(&mut sheet, "high").is_ok());
+        assert_eq!(sheet.entry((0, 3)), "");
+        sheet.take_changes();
+
+        assert!(history.undo(&mut sheet));
+        assert_eq!(sheet.filter().unwrap().range, Range::new((0, 3), (0, 5)));
+        whatif::show_scenario(&mut sheet, "high").unwrap();
+        assert_eq!(sheet.shown((1, 0)), "20");
+
+        // And redoing takes them away again
+        whatif::revert_scenario(&mut sheet).unwrap();
+        sheet.take_changes();
+        assert!(history.redo(&mut sheet));
+        assert_eq!(sheet.filter(), None);
+    }
+}
@@ -0,0 +1,147 @@
+use crate::model::sheet::Sheet;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Direction {
+    /// The cells a formula reads from
+    Precedents,
+    /// The formulas reading from a cell
+    Dependents,
+}
+
+/// Cells linked to a cell through formula references, found one level
+/// further every time the trace is repeated
+#[derive(Debug)]
+pub struct Links {
+    pub origin: (usize, usize),
+    pub direction: Direction,
+    /// Every linked cell found so far, nearest first
+    pub cells: Vec<(usize, usize)>,
+    /// The cells found by the last level, the next level starts from these
+    frontier: Vec<(usize, usize)>,
+    /// Index in `cells` of the cell jumped to last
+    current: Option<usize>,
+}
+
+impl Links {
+    /// The cells directly linked to `origin`
+    pub fn new(sheet: &Sheet, origin: (usize, usize), direction: Direction) -> Self {
+        let mut links = Links {
+            origin,
+            direction,
+            cells: vec![],
+            frontier: vec![origin],
+            current: None,
+        };
+        links.expand(sheet);
+        links
+    }
+
+    /// The other cells on the cycle of references that made the formula at
+    /// `origin` fail, in the order the values flow around it
+    pub fn cycle(sheet: &Sheet, origin: (usize, usize)) -> Option<Self> {
+        let path = sheet.cycle(origin)?;
+        Some(Links {
+            origin,
+            direction: Direction::Dependents,
+            cells: path[1..path.len() - 1].to_vec(),
+            frontier: vec![],
+            current: None,
+        })
+    }
+
+    /// Adds the cells linked to the ones found last
+    pub fn expand(&mut self, sheet: &Sheet) {
+        let mut next = vec![];
+        for pos in std::mem::take(&mut self.frontier) {
+            let linked = match self.direction {
+                Direction::Precedents => sheet.precedents(pos),
+                Direction::Dependents => sheet.dependents(pos),
+            };
+            for p in linked {
+                if p != self.origin && !self.cells.contains(&p) {
+                    self.cells.push(p);
+                    next.push(p);
+                }
+            }
+        }
+        self.frontier = next;
+    }
+
+    /// The next linked cell to jump to, or the previous one
+    pub fn jump(&mut self, forward: bool) -> Option<(usize, usize)> {
+        let len = self.cells.len();
+        if len == 0 {
+            return None;
+        }
+        let i = match (self.current, forward) {
+            (None, true) => 0,
+            (None, false) => len - 1,
+            (Some(i), true) => (i + 1) % len,
+            (Some(i), false) => (i + len - 1) % len,
+        };
+        self.current = Some(i);
+        Some(self.cells[i])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sheet() -> Sheet {
+        let mut sheet = Sheet::new();
+        sheet.enter((0, 0), "1");
+        sheet.enter((1, 0), "2");
+        sheet.enter((2, 0), "=A1+SUM(B1:B3)");
+        sheet.enter((3, 0), "=C1*2");
+        sheet.enter((4, 0), "=INDIRECT(\"A1\")");
+        sheet
+    }
+
+    #[test]
+    fn precedents_level_by_level() {
+        let sheet = sheet();
+        let mut links = Links::new(&sheet, (3, 0), Direction::Precedents);
+        assert_eq!(links.cells, [(2, 0)]);
+        links.expand(&sheet);
+        assert_eq!(links.cells, [(2, 0), (0, 0), (1, 0)]);
+        links.expand(&sheet);
+        assert_eq!(links.cells.len(), 3);
+    }
+
+    #[test]
+    fn dependents_level_by_level() {
+        let sheet = sheet();
+        let mut links = Links::new(&sheet, (0, 0), Direction::Dependents);
+        assert_eq!(links.cells, [(2, 0), (4, 0)]);
+        links.expand(&sheet);
+        assert_eq!(links.cells, [(2, 0), (4, 0), (3, 0)]);
+    }
+
+    #[test]
+    fn jumping_wraps_around() {
+        let sheet = sheet();
+        let mut links = Links::new(&sheet, (0, 0), Direction::Dependents);
+        assert_eq!(links.jump(false), Some((4, 0)));
+        assert_eq!(links.jump(true), Some((2, 0)));
+        assert_eq!(links.jump(true), Some((4, 0)));
+        assert_eq!(links.jump(true), Some((2, 0)));
+        assert_eq!(
+            Links::new(&sheet, (3, 0), Direction::Dependents).jump(true),
+            None
+        );
+    }
+
+    #[test]
+    fn cycles_in_the_order_values_flow() {
+        let mut sheet = Sheet::new();
+        sheet.enter((0, 0), "=C1+1");
+        sheet.enter((1, 0), "=A1");
+        sheet.enter((2, 0), "=B1");
+        assert_eq!(
+            Links::cycle(&sheet, (0, 0)).unwrap().cells,
+            [(1, 0), (2, 0)]
+        );
+        assert!(Links::cycle(&sheet, (3, 0)).is_none());
+    }
+}
@@ -1,11 +1,27 @@
 use std::io;
 
+mod clipboard;
+mod command;
+mod history;
+mod links;
 mod parser;
 
-use crate::model::sheet::Sheet;
+use crate::model::{
+    calc::Trace,
+    fill,
+    filter::AutoFilter,
+    reference::Range,
+    sheet::{Sheet, COLUMNS, ROWS},
+    shift::{Axis, Shift},
+    solver, sort, whatif,
+};
 use crate::ui::Ui;
 use crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
 
+use self::clipboard::Clipboard;
+use self::command::{Command, FilterCommand, ScenarioCommand, Setting};
+use self::history::{History, DEFAULT_DEPTH};
+use self::links::Links;
 use self::parser::parse;
 
 #[derive(Debug)]
@@ -14,6 +30,12 @@ pub struct Controller {
     sheet: Sheet,
     entry: String,
     selection: (usize, usize),
+    /// The evaluation being stepped through and the current step
+    debugger: Option<(Trace, usize)>,
+    /// Precedents or dependents shown over the sheet
+    links: Option<Links>,
+    history: History,
+    clipboard: Option<Clipboard>,
 }
 
 impl Controller {
@@ -27,17 +49,245 @@ impl Controller {
             sheet,
             entry,
             selection: (0, 0),
+            debugger: None,
+            links: None,
+            history: History::new(DEFAULT_DEPTH),
+            clipboard: None,
         }
     }
 
     pub fn set_entry(&mut self) -> io::Result<()> {
         self.entry.clear();
-        self.sheet[self.selection.0][self.selection.1]
-            .entry(&mut self.entry)
-            .unwrap();
+        if let Some(cell) = self.sheet.get(self.selection) {
+            cell.entry(&mut self.entry).unwrap();
+        }
         self.ui.set_entry(&self.entry)
     }
 
+    /// Runs a command parsed from the entry, returns false if it was not
+    /// understood or failed
+    fn command(&mut self, command: Option<Command>) -> bool {
+        let mut iteration = self.sheet.settings.iteration;
+        match command {
+            Some(Command::Set(Setting::BigInt(b))) => self.sheet.set_big_int(b),
+            Some(Command::Set(Setting::Rounding(r))) => self.sheet.set_rounding(r),
+            Some(Command::Set(Setting::Seed(s))) => self.sheet.set_seed(s),
+            Some(Command::Set(Setting::Iterate(enabled))) => {
+                iteration.enabled = enabled;
+                self.sheet.set_iteration(iteration);
+            }
+            Some(Command::Set(Setting::MaxIterations(max))) => {
+                iteration.max_iterations = max;
+                self.sheet.set_iteration(iteration);
+            }
+            Some(Command::Set(Setting::History(depth))) => self.history.set_depth(depth),
+            Some(Command::Set(Setting::Tolerance(tolerance))) => {
+                iteration.tolerance = tolerance;
+                self.sheet.set_iteration(iteration);
+            }
+            Some(Command::Format(f)) => self.sheet.set_format(f, self.selection),
+            Some(Command::GoalSeek {
+                formula,
+                target,
+                input,
+            }) => {
+                return solver::goal_seek(&mut self.sheet, formula, target, input).is_ok();
+            }
+            Some(Command::Solve {
+                objective,
+                goal,
+                variables,
+            }) => {
+                return solver::solve(&mut self.sheet, objective, goal, &variables).is_ok();
+            }
+            Some(Command::DataTable {
+                range,
+                row_input,
+                column_input,
+            }) => {
+                return whatif::data_table(&mut self.sheet, range, row_input, column_input).is_ok();
+            }
+            Some(Command::Width(w)) => self.sheet.set_column_width(self.selection.0, w),
+            Some(Command::Insert(axis, count)) => {
+                return self.shift(Shift::insert(axis, self.along(axis), count))
+            }
+            Some(Command::Delete(axis, count)) => {
+                return self.shift(Shift::delete(axis, self.along(axis), count))
+            }
+            Some(Command::Copy(range)) => {
+                let clipboard =
+                    Clipboard::copy(&self.sheet, range.unwrap_or(Range::cell(self.selection)));
+                let copied = self.ui.set_clipboard(&clipboard.text(&self.sheet)).is_ok();
+                self.clipboard = Some(clipboard);
+                return copied;
+            }
+            Some(Command::Cut(range)) => {
+                let clipboard =
+                    Clipboard::cut(&self.sheet, range.unwrap_or(Range::cell(self.selection)));
+                let copied = self.ui.set_clipboard(&clipboard.text(&self.sheet)).is_ok();
+                self.clipboard = Some(clipboard);
+                return copied;
+            }
+            Some(Command::Paste(options)) => {
+                let Some(clipboard) = &self.clipboard else {
+                    return false;
+                };
+                if !clipboard.paste(&mut self.sheet, self.selection, options) {
+                    return false;
+                }
+                // Cut cells can only be moved once
+                if clipboard.cut {
+                    self.clipboard = None;
+                }
+            }
+            Some(Command::Fill(direction, range)) => {
+                return fill::fill(&mut self.sheet, range, direction)
+            }
+            Some(Command::Sort {
+                range,
+                keys,
+                header,
+            }) => return sort::sort(&mut self.sheet, range, &keys, header),
+            Some(Command::Filter(f)) => {
+                let current = self.sheet.filter().cloned();
+                let filter = match f {
+                    FilterCommand::On(range) => Some(AutoFilter::new(range)),
+                    FilterCommand::Off => None,
+                    FilterCommand::Apply => current,
+                    FilterCommand::Set(x, _) | FilterCommand::Clear(x) => {
+                        let Some(mut filter) =
+                            current.filter(|f| (f.range.from.0..=f.range.to.0).contains(&x))
+                        else {
+                            return false;
+                        };
+                        match f {
+                            FilterCommand::Set(_, criterion) => {
+                                filter.criteria.insert(x, criterion)
+                            }
+                            _ => filter.criteria.remove(&x),
+                        };
+                        Some(filter)
+                    }
+                };
+                self.sheet.set_filter(filter);
+                // The selection moves off a row that is now hidden
+                if self.sheet.is_hidden(self.selection.1) {
+                    let below = self.next_row(false);
+                    self.selection.1 = if below != self.selection.1 {
+                        below
+                    } else {
+                        self.next_row(true)
+                    };
+                    return self
+                        .ui
+                        .set_selection(self.selection.0, self.selection.1, &self.sheet)
+                        .is_ok();
+                }
+            }
+            Some(Command::Undo) => return self.undo(false),
+            Some(Command::Redo) => return self.undo(true),
+            Some(Command::Debug) => match self.sheet.trace(self.selection) {
+                Some(trace) => self.debugger = Some((trace, 0)),
+                None => return false,
+            },
+            Some(Command::Trace(direction)) => {
+                match &mut self.links {
+                    Some(l) if l.origin == self.selection && l.direction == direction => {
+                        l.expand(&self.sheet)
+                    }
+                    _ => self.links = Some(Links::new(&self.sheet, self.selection, direction)),
+                }
+                let cells = self.links.as_ref().map_or(&[][..], |l| &l.cells);
+                return self.ui.set_links(cells, &self.sheet).is_ok();
+            }
+            Some(Command::Scenario(s)) => {
+                let sheet = &mut self.sheet;
+                let res = match s {
+                    ScenarioCommand::Add { name, cells } => {
+                        whatif::add_scenario(sheet, &name, cells)
+                    }
+                    ScenarioCommand::Delete(name) => whatif::delete_scenario(sheet, &name),
+                    ScenarioCommand::Show(name) => whatif::show_scenario(sheet, &name),
+                    ScenarioCommand::Revert => whatif::revert_scenario(sheet),
+                    ScenarioCommand::Summary(results) => {
+                        whatif::scenario_summary(sheet, self.selection, &results)
+                    }
+                };
+                return res.is_ok();
+            }
+            None => return false,
+        }
+        true
+    }
+
+    /// The row above or below the selected one, skipping the rows hidden by
+    /// the autofilter. Stays on the selected row if there is none.
+    fn next_row(&self, up: bool) -> usize {
+        let y = self.selection.1;
+        let shown = |y: &usize| !self.sheet.is_hidden(*y);
+        match up {
+            true => (0..y).rev().find(shown),
+            false => (y + 1..ROWS).find(shown),
+        }
+        .unwrap_or(y)
+    }
+
+    /// Undoes the last step, or redoes the last one undone. Highlighted links
+    /// may be out of date afterwards.
+    fn undo(&mut self, redo: bool) -> bool {
+        let done = match redo {
+            false => self.history.undo(&mut self.sheet),
+            true => self.history.redo(&mut self.sheet),
+        };
+        self.links = None;
+        done && self.ui.set_links(&[], &self.sheet).is_ok()
+    }
+
+    /// The selected column or row
+    fn along(&self, axis: Axis) -> usize {
+        match axis {
+            Axis::Column => self.selection.0,
+            Axis::Row => self.selection.1,
+        }
+    }
+
+    /// Inserts or deletes rows or columns, the highlighted links no longer
+    /// point at the right cells afterwards
+    fn shift(&mut self, shift: Shift) -> bool {
+        if !self.sheet.shift(shift) {
+            return false;
+        }
+        self.links = None;
+        self.ui.set_links(&[], &self.sheet).is_ok()
+    }
+
+    /// Handles a key while stepping through a formula
+    fn debugger_key(&mut self, code: KeyCode) -> io::Result<()> {
+        if let Some((trace, step)) = &mut self.debugger {
+            match code {
+                KeyCode::Down | KeyCode::Right | KeyCode::Char('n') | KeyCode::Char(' ') => {
+                    *step = (*step + 1).min(trace.steps.len().saturating_sub(1));
+                }
+                KeyCode::Up | KeyCode::Left | KeyCode::Char('p') => *step = step.saturating_sub(1),
+                KeyCode::Esc | KeyCode::Char('q') => {
+                    self.debugger = None;
+                    return self.ui.redraw(&self.sheet);
+                }
+                _ => (),
+            }
+        }
+        self.draw_debugger()
+    }
+
+    fn draw_debugger(&mut self) -> io::Result<()> {
+        match &self.debugger {
+            Some((trace, step)) => self
+                .ui
+                .draw_trace(trace, *step, self.selection, &self.sheet),
+            None => Ok(()),
+        }
+    }
+
     pub fn run(&mut self) -> io::Result<()> {
         self.ui.redraw(&self.sheet).expect("Unable to draw ui");
 
@@ -53,9 +303,19 @@ impl Controller {
                         code: KeyCode::Char('c'),
                         ..
                     } => running = false,
+                    KeyEvent {
+                        modifiers: KeyModifiers::CONTROL,
+                        code: KeyCode::Char(ch @ ('z' | 'y')),
+                        ..
+                    } if self.debugger.is_none() => {
+                        self.undo(ch == 'y');
+                        self.ui.redraw(&self.sheet)?;
+                        self.set_entry()?;
+                    }
+                    KeyEvent { code, .. } if self.debugger.is_some() => self.debugger_key(code)?,
                     KeyEvent { code, .. } => match code {
                         KeyCode::Up => {
-                            self.selection.1 = self.selection.1.saturating_sub(1);
+                            self.selection.1 = self.next_row(true);
                             self.ui.set_selection(
                                 self.selection.0,
                                 self.selection.1,
@@ -64,7 +324,7 @@ impl Controller {
                             self.set_entry()?;
                         }
                         KeyCode::Down => {
-                            self.selection.1 += 1;
+                            self.selection.1 = self.next_row(false);
                             self.ui.set_selection(
                                 self.selection.0,
                                 self.selection.1,
@@ -73,7 +333,7 @@ impl Controller {
                             self.set_entry()?;
                         }
                         KeyCode::Right => {
-                            self.selection.0 += 1;
+                            self.selection.0 = (self.selection.0 + 1).min(COLUMNS - 1);
                             self.ui.set_selection(
                                 self.selection.0,
                                 self.selection.1,
@@ -91,7 +351,39 @@ impl Controller {
                             self.set_entry()?;
                         }
                         KeyCode::Enter => {
-                            self.sheet.insert_cell(parse(&self.entry), self.selection);
+                            if let Some(cmd) = self.entry.strip_prefix(':') {
+                                if self.command(command::parse(cmd)) {
+                                    self.set_entry()?;
+                                }
+                            } else {
+                                let (cell, format) =
+                                    parse(&self.entry, self.selection, &mut self.sheet.formulas);
+                                if let Some(f) = format {
+                                    self.sheet.set_format(f, self.selection);
+                                }
+                                self.sheet.insert_cell(cell, self.selection);
+                                if let Some(cycle) = Links::cycle(&self.sheet, self.selection) {
+                                    self.ui.set_links(&cycle.cells, &self.sheet)?;
+                                    self.links = Some(cycle);
+                                }
+                            }
+                            self.ui.redraw(&self.sheet)?;
+                            self.draw_debugger()?;
+                        }
+                        KeyCode::Tab | KeyCode::BackTab => {
+                            let forward = code == KeyCode::Tab;
+                            if let Some(pos) = self.links.as_mut().and_then(|l| l.jump(forward)) {
+                                self.selection = pos;
+                                self.ui.set_selection(pos.0, pos.1, &self.sheet)?;
+                                self.set_entry()?;
+                            }
+                        }
+                        KeyCode::Esc => {
+                            self.links = None;
+                            self.ui.set_links(&[], &self.sheet)?;
+                        }
+                        KeyCode::F(9) => {
+                            self.sheet.recalculate();
                             self.ui.redraw(&self.sheet)?;
                         }
                         KeyCode::Backspace => {
@@ -111,6 +403,9 @@ impl Controller {
                     self.ui.resize(width, height, &self.sheet)?
                 }
             }
+
+            // Everything changed by the event is undone in one step
+            self.history.record(self.sheet.take_changes());
         }
 
         self.ui.finish().expect("Unable to finish ui");
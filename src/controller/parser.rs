@@ -1,19 +1,137 @@
 use std::rc::Rc;
 
-use crate::model::{sheet::{Cell, Expression}, calc};
+use crate::model::{
+    calc::{self, FormulaCache},
+    format::Format,
+    number::Number,
+    sheet::{Cell, Expression},
+};
 
-pub fn parse(s: &str) -> Cell {
+/// Parses an entry typed into the cell at `pos`, together with the format it
+/// implies if any
+pub fn parse(s: &str, pos: (usize, usize), formulas: &mut FormulaCache) -> (Cell, Option<Format>) {
     // TODO: Impement back the non expression types
     match s.chars().next() {
-        Some(c) => if c == '=' {
-            let ex = Expression::new(s.to_owned());
-            Cell::Expression(Rc::new(ex), Err(calc::ExecutionError::NotExecuted))
-        } else {
-            match s.parse() {
-                Ok(o) => Cell::Val(o),
-                Err(_) => Cell::String(s.to_string()),
+        Some(c) => {
+            if c == '=' {
+                let ex = Expression::new(s.to_owned(), pos, formulas);
+                (
+                    Cell::Expression(Rc::new(ex), Err(calc::ExecutionError::NotExecuted)),
+                    None,
+                )
+            } else if let Some((n, format)) = number(s) {
+                (Cell::Val(n), format)
+            } else {
+                (Cell::String(s.to_string()), None)
             }
-        },
-        None => Cell::None,
+        }
+        None => (Cell::None, None),
+    }
+}
+
+/// Parses numbers the way they are commonly written, `12%`, `$1,200.50`,
+/// `(300)` for negative amounts, `1.2e6` and `1_000_000`
+fn number(s: &str) -> Option<(Number, Option<Format>)> {
+    let s = s.trim();
+
+    if let Some(inner) = s.strip_prefix('(').and_then(|r| r.strip_suffix(')')) {
+        let (n, format) = number(inner)?;
+        let format = match format {
+            None | Some(Format::Thousands) => Format::Accounting,
+            Some(f) => f,
+        };
+        return Some((n.neg(true).ok()?, Some(format)));
+    }
+
+    if let Some(inner) = s.strip_suffix('%') {
+        let (n, _) = number(inner)?;
+        return Some((n.percent().ok()?, Some(Format::Percent)));
+    }
+
+    let (sign, rest) = match s.strip_prefix('-') {
+        Some(r) => ("-", r),
+        None => ("", s),
+    };
+    if let Some(amount) = rest.strip_prefix('$') {
+        let n = format!("{}{}", sign, ungroup(amount)?)
+            .parse::<Number>()
+            .ok()?;
+        return Some((Format::Currency.coerce(n), Some(Format::Currency)));
+    }
+
+    if s.contains(',') {
+        let n = format!("{}{}", sign, ungroup(rest)?).parse().ok()?;
+        return Some((n, Some(Format::Thousands)));
+    }
+
+    let n = s.parse().ok()?;
+    let format = s.contains(['e', 'E']).then_some(Format::Scientific);
+    Some((n, format))
+}
+
+/// Removes the `,` between groups of three digits, fails if they are misplaced
+fn ungroup(s: &str) -> Option<String> {
+    let (int, frac) = s.split_at(s.find('.').unwrap_or(s.len()));
+    let mut groups = int.split(',');
+
+    let first = groups.next()?;
+    if first.is_empty() || (first.len() > 3 && int.contains(',')) {
+        return None;
+    }
+    let mut digits = first.to_owned();
+    for g in groups {
+        if g.len() != 3 {
+            return None;
+        }
+        digits.push_str(g);
+    }
+
+    Some(digits + frac)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entered(s: &str) -> (String, Option<Format>) {
+        let (cell, format) = parse(s, (0, 0), &mut FormulaCache::default());
+        (cell.to_string(), format)
+    }
+
+    #[test]
+    fn numbers_as_commonly_written() {
+        assert_eq!(entered("12"), ("12".to_owned(), None));
+        assert_eq!(
+            entered("12.5%"),
+            ("0.125".to_owned(), Some(Format::Percent))
+        );
+        assert_eq!(
+            entered("1.2e6"),
+            ("1200000".to_owned(), Some(Format::Scientific))
+        );
+        assert_eq!(
+            entered("1,234,567"),
+            ("1234567".to_owned(), Some(Format::Thousands))
+        );
+        assert_eq!(
+            entered("-$1,200.5"),
+            ("-1200.5".to_owned(), Some(Format::Currency))
+        );
+        assert_eq!(
+            entered("(300)"),
+            ("-300".to_owned(), Some(Format::Accounting))
+        );
+        assert_eq!(entered("1_000_000"), ("1000000".to_owned(), None));
+    }
+
+    #[test]
+    fn anything_else_is_text() {
+        for s in ["1,23", "12,3456", ",123", "1__0", "12a", "$", "(1"] {
+            assert_eq!(entered(s), (s.to_owned(), None), "{}", s);
+        }
+        assert!(matches!(
+            parse("", (0, 0), &mut FormulaCache::default()).0,
+            Cell::None
+        ));
     }
 }
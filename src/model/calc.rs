@@ -1,99 +1,387 @@
-use super::sheet::Sheet;
+use std::{
+    cmp::Ordering,
+    collections::HashMap,
+    iter::Peekable,
+    rc::{Rc, Weak},
+    str::FromStr,
+    vec,
+};
+
+use super::{
+    function::{Cache, Function},
+    number::Number,
+    optimize::optimize,
+    printer,
+    random::Rng,
+    reference::{self, CellRef, Range, INVALID_REFERENCE},
+    sheet::{Cell, Sheet, ROWS},
+    shift::{self, Relocation},
+    value::Value,
+};
 
 #[derive(Debug, PartialEq, Clone)]
 pub enum Node {
-    Err,
     Add,
     Sub,
     Mul,
     Div,
-    Cell(usize, usize),
-    Val(i64),
+    /// Unary minus
+    Neg,
+    /// Postfix `%`, divides by a hundred
+    Percent,
+    Eq,
+    Ne,
+    Lt,
+    Gt,
+    Le,
+    Ge,
+    Cell(CellRef),
+    Range(CellRef, CellRef),
+    Val(Value),
+    /// Calls the function with the given number of arguments from the stack
+    Call(Function, usize),
+    /// Adds the given number of operands from the stack, like a chain of [Node::Add]
+    Sum(usize),
+    /// Continues at the given instruction
+    Jump(usize),
+    /// Pops a condition and jumps if it is true
+    JumpIf(usize),
+    /// Pops a condition and jumps if it is false
+    JumpIfNot(usize),
+    /// Any error until the matching [Node::EndCatch] jumps to the given
+    /// instruction instead of ending the evaluation
+    Catch(usize),
+    /// Reads the value on top of the stack, still under the innermost
+    /// [Node::Catch], and then removes that handler
+    EndCatch,
 }
 
 impl Node {
-    fn precedence(&self) -> u8 {
+    /// How tightly the operator binds, lower numbers bind tighter
+    pub fn precedence(&self) -> u8 {
         match self {
-            Node::Err => 0,
             Node::Add => 4,
             Node::Sub => 4,
             Node::Mul => 3,
             Node::Div => 3,
-            Node::Cell(_, _) => 0,
-            Node::Val(_) => 0,
+            Node::Eq | Node::Ne | Node::Lt | Node::Gt | Node::Le | Node::Ge => 5,
+            _ => 0,
         }
     }
 
     fn is_op(&self) -> bool {
         match self {
-            Node::Err => false,
-            Node::Cell(_, _) => false,
+            Node::Cell(_) => false,
+            Node::Range(_, _) => false,
             Node::Val(_) => false,
+            Node::Call(_, _) => false,
+            Node::Sum(_) => false,
+            Node::Jump(_) | Node::JumpIf(_) | Node::JumpIfNot(_) => false,
+            Node::Catch(_) | Node::EndCatch => false,
             _ => true,
         }
     }
 
-    fn eval(&self) -> Result<i64, ExecutionError> {
+    /// Pushes a value or a reference, resolved for the formula at `pos`
+    fn eval(&self, pos: (usize, usize)) -> Result<Operand, ExecutionError> {
+        let resolve = |r: &CellRef| r.resolve(pos).ok_or(ExecutionError::InvalidReference);
         match self {
-            Node::Cell(_, _) => todo!(),
-            Node::Val(v) => Ok(*v),
+            Node::Cell(r) => Ok(Operand::Ref(Range::cell(resolve(r)?))),
+            Node::Range(a, b) => Ok(Operand::Ref(Range::new(resolve(a)?, resolve(b)?))),
+            Node::Val(v) => Ok(Operand::Value(v.clone())),
             _ => Err(ExecutionError::NotImpemented),
         }
     }
 
     // TODO: Combine eval and calc
-    fn calc(&self, stack: &mut Vec<i64>) -> Result<i64, ExecutionError> {
+    fn calc(&self, stack: &mut Vec<Operand>, ctx: &mut Context) -> Result<Value, ExecutionError> {
         use ExecutionError::OutOfStack;
+        let big = ctx.sheet.settings.big_int;
+        let rhs = ctx.value(stack.pop().ok_or(OutOfStack)?)?;
+        if let Node::Neg | Node::Percent = self {
+            return self.apply(&Value::Bool(false), &rhs, big);
+        }
+        let lhs = ctx.value(stack.pop().ok_or(OutOfStack)?)?;
+        self.apply(&lhs, &rhs, big)
+    }
+
+    /// Applies the operator to values, `lhs` is ignored by the unary ones
+    pub fn apply(&self, lhs: &Value, rhs: &Value, big: bool) -> Result<Value, ExecutionError> {
+        let ord = || lhs.compare(rhs);
         match self {
-            Node::Add => Ok(stack.pop().ok_or(OutOfStack)? + stack.pop().ok_or(OutOfStack)?),
-            Node::Sub => {
-                let sub = stack.pop().ok_or(OutOfStack)?;
-                Ok(stack.pop().ok_or(OutOfStack)? - sub)
-            }
-            Node::Mul => Ok(stack.pop().ok_or(OutOfStack)? * stack.pop().ok_or(OutOfStack)?),
-            Node::Div => {
-                let nom = stack.pop().ok_or(OutOfStack)?;
-                Ok(stack.pop().ok_or(OutOfStack)? / nom)
-            }
+            Node::Neg => return rhs.number()?.neg(big).map(Value::Number),
+            Node::Percent => return rhs.number()?.percent().map(Value::Number),
+            _ => (),
+        }
+        match self {
+            Node::Add => lhs.number()?.add(&rhs.number()?, big).map(Value::Number),
+            Node::Sub => lhs.number()?.sub(&rhs.number()?, big).map(Value::Number),
+            Node::Mul => lhs.number()?.mul(&rhs.number()?, big).map(Value::Number),
+            Node::Div => lhs.number()?.div(&rhs.number()?, big).map(Value::Number),
+            Node::Eq => Ok(Value::Bool(ord() == Ordering::Equal)),
+            Node::Ne => Ok(Value::Bool(ord() != Ordering::Equal)),
+            Node::Lt => Ok(Value::Bool(ord() == Ordering::Less)),
+            Node::Gt => Ok(Value::Bool(ord() == Ordering::Greater)),
+            Node::Le => Ok(Value::Bool(ord() != Ordering::Greater)),
+            Node::Ge => Ok(Value::Bool(ord() != Ordering::Less)),
             _ => Err(ExecutionError::NotImpemented),
         }
     }
+}
+
+/// A parsed formula before it is compiled to [ByteCode]
+#[derive(Debug, PartialEq, Clone)]
+pub enum Expr {
+    Val(Value),
+    Cell(CellRef),
+    Range(CellRef, CellRef),
+    /// A binary operator, always one of the operator [Node]s
+    Op(Node, Box<Expr>, Box<Expr>),
+    Neg(Box<Expr>),
+    Percent(Box<Expr>),
+    Call(Function, Vec<Expr>),
+    /// A chain of additions, only produced by [optimize]
+    Sum(Vec<Expr>),
+}
 
-    fn gt(&self, other: &Self) -> bool {
-        self.precedence() < other.precedence()
+impl Expr {
+    fn compile(&self, code: &mut Vec<Node>) {
+        match self {
+            Expr::Val(v) => code.push(Node::Val(v.clone())),
+            Expr::Cell(r) => code.push(Node::Cell(*r)),
+            Expr::Range(a, b) => code.push(Node::Range(*a, *b)),
+            Expr::Op(op, lhs, rhs) => {
+                lhs.compile(code);
+                rhs.compile(code);
+                code.push(op.clone());
+            }
+            Expr::Neg(e) => {
+                e.compile(code);
+                code.push(Node::Neg);
+            }
+            Expr::Percent(e) => {
+                e.compile(code);
+                code.push(Node::Percent);
+            }
+            Expr::Call(Function::If, args) => {
+                args[0].compile(code);
+                let to_else = placeholder(code);
+                args[1].compile(code);
+                let to_end = placeholder(code);
+                code[to_else] = Node::JumpIfNot(code.len());
+                match args.get(2) {
+                    Some(e) => e.compile(code),
+                    None => code.push(Node::Val(Value::Bool(false))),
+                }
+                code[to_end] = Node::Jump(code.len());
+            }
+            Expr::Call(Function::IfError, args) => {
+                let catch = placeholder(code);
+                args[0].compile(code);
+                code.push(Node::EndCatch);
+                let to_end = placeholder(code);
+                code[catch] = Node::Catch(code.len());
+                args[1].compile(code);
+                code[to_end] = Node::Jump(code.len());
+            }
+            Expr::Call(f @ (Function::And | Function::Or), args) => {
+                // Stops at the first argument deciding the result, which is
+                // false for AND and true for OR. Ranges are first reduced to
+                // the result of the function over their values.
+                let and = *f == Function::And;
+                let mut to_decided = vec![];
+                for a in args {
+                    a.compile(code);
+                    if matches!(
+                        a,
+                        Expr::Range(_, _) | Expr::Call(Function::Indirect | Function::Offset, _)
+                    ) {
+                        code.push(Node::Call(*f, 1));
+                    }
+                    to_decided.push(placeholder(code));
+                }
+                code.push(Node::Val(Value::Bool(and)));
+                let to_end = placeholder(code);
+                for i in to_decided {
+                    code[i] = match and {
+                        true => Node::JumpIfNot(code.len()),
+                        false => Node::JumpIf(code.len()),
+                    };
+                }
+                code.push(Node::Val(Value::Bool(!and)));
+                code[to_end] = Node::Jump(code.len());
+            }
+            Expr::Call(f, args) => {
+                for a in args {
+                    a.compile(code);
+                }
+                code.push(Node::Call(*f, args.len()));
+            }
+            Expr::Sum(terms) => {
+                for t in terms {
+                    t.compile(code);
+                }
+                code.push(Node::Sum(terms.len()));
+            }
+        }
     }
 }
 
-pub fn parse(expr: &str) -> ByteCode {
-    let mut expression = vec![];
-    let mut stack: Vec<Node> = vec![];
+/// Reserves room for a jump whose target is not known yet
+fn placeholder(code: &mut Vec<Node>) -> usize {
+    code.push(Node::Jump(usize::MAX));
+    code.len() - 1
+}
+
+/// Lowest binding operator precedence, see [Node::precedence]
+const LOWEST_PRECEDENCE: u8 = 5;
+
+struct Parser {
+    tokens: Peekable<vec::IntoIter<Token>>,
+}
 
-    for n in Lexer::new(expr) {
-        if n == Node::Err {
-            return ByteCode { code: Err(()) };
-        } else if !n.is_op() {
-            expression.push(n);
+impl Parser {
+    fn expect(&mut self, token: Token) -> Result<(), ()> {
+        if self.tokens.next() == Some(token) {
+            Ok(())
         } else {
-            while let Some(s) = stack.last() {
-                if s.gt(&n) {
-                    expression.push(stack.pop().unwrap())
+            Err(())
+        }
+    }
+
+    /// Parses operators binding at `precedence` or tighter
+    fn binary(&mut self, precedence: u8) -> Result<Expr, ()> {
+        if precedence == 0 {
+            return self.postfix();
+        }
+
+        let mut lhs = self.binary(precedence - 1)?;
+        while let Some(Token::Op(op)) = self.tokens.peek() {
+            if op.precedence() != precedence {
+                break;
+            }
+            let op = op.clone();
+            self.tokens.next();
+            let rhs = self.binary(precedence - 1)?;
+            lhs = Expr::Op(op, Box::new(lhs), Box::new(rhs));
+        }
+        Ok(lhs)
+    }
+
+    /// Parses an operand followed by any number of `%`
+    fn postfix(&mut self) -> Result<Expr, ()> {
+        let mut e = self.primary()?;
+        while self.tokens.peek() == Some(&Token::Percent) {
+            self.tokens.next();
+            e = Expr::Percent(Box::new(e));
+        }
+        Ok(e)
+    }
+
+    fn primary(&mut self) -> Result<Expr, ()> {
+        match self.tokens.next().ok_or(())? {
+            Token::Val(v) => Ok(Expr::Val(v)),
+            Token::Cell(a) => {
+                if self.tokens.peek() != Some(&Token::Colon) {
+                    return Ok(Expr::Cell(a));
+                }
+                self.tokens.next();
+                match self.tokens.next().ok_or(())? {
+                    Token::Cell(b) => Ok(Expr::Range(a, b)),
+                    _ => Err(()),
+                }
+            }
+            Token::Op(Node::Sub) => Ok(Expr::Neg(Box::new(self.postfix()?))),
+            Token::LParen => {
+                let e = self.binary(LOWEST_PRECEDENCE)?;
+                self.expect(Token::RParen)?;
+                Ok(e)
+            }
+            Token::Ident(name) => {
+                let f = Function::from_name(&name).ok_or(())?;
+                self.expect(Token::LParen)?;
+
+                let mut args = vec![];
+                if self.tokens.peek() == Some(&Token::RParen) {
+                    self.tokens.next();
                 } else {
-                    break;
+                    loop {
+                        args.push(self.binary(LOWEST_PRECEDENCE)?);
+                        match self.tokens.next().ok_or(())? {
+                            Token::Comma => (),
+                            Token::RParen => break,
+                            _ => return Err(()),
+                        }
+                    }
+                }
+
+                let (min, max) = f.arity();
+                if args.len() < min || args.len() > max {
+                    return Err(());
                 }
+                Ok(Expr::Call(f, args))
             }
-            stack.push(n)
+            _ => Err(()),
         }
     }
-    while let Some(s) = stack.pop() {
-        expression.push(s)
-    }
+}
+
+fn compile(tokens: Vec<Token>) -> ByteCode {
+    let mut parser = Parser {
+        tokens: tokens.into_iter().peekable(),
+    };
+
+    let tree = parser
+        .binary(LOWEST_PRECEDENCE)
+        .and_then(|e| match parser.tokens.next() {
+            None => Ok(e),
+            Some(_) => Err(()),
+        });
+    let code = tree.clone().map(|e| {
+        let mut code = vec![];
+        optimize(e).compile(&mut code);
+        code
+    });
 
     ByteCode {
-        code: Ok(expression),
+        code,
+        tree: tree.ok(),
+        cache: Cache::default(),
     }
 }
 
-#[derive(Debug, Clone, Copy)]
+/// Shares one compiled formula between all cells whose formulas are the same
+/// apart from where they are, as after filling a formula down a column
+#[derive(Debug, Default)]
+pub struct FormulaCache {
+    /// By the relative tokens of the formula, written out with [Debug] since
+    /// values that compare equal like `1.0` and `1.00` have to be kept apart
+    compiled: HashMap<String, Weak<ByteCode>>,
+    /// Entries of formulas no longer in use are removed when reaching this
+    prune_at: usize,
+}
+
+impl FormulaCache {
+    /// Compiles the formula `expr` written in the cell at `pos`, or gives the
+    /// already compiled one of an equivalent formula
+    pub fn compile(&mut self, expr: &str, pos: (usize, usize)) -> Rc<ByteCode> {
+        let tokens: Vec<Token> = Lexer::new(expr, pos).collect();
+        let key = format!("{:?}", tokens);
+        if let Some(code) = self.compiled.get(&key).and_then(Weak::upgrade) {
+            return code;
+        }
+
+        if self.compiled.len() >= self.prune_at {
+            self.compiled.retain(|_, code| code.strong_count() > 0);
+            self.prune_at = (self.compiled.len() * 2).max(64);
+        }
+        let code = Rc::new(compile(tokens));
+        self.compiled.insert(key, Rc::downgrade(&code));
+        code
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum ExecutionError {
     CompilationError,
     NotImpemented,
@@ -103,50 +391,353 @@ pub enum ExecutionError {
     NotExecuted,
     Cyclic,
     CellNotFound,
+    Overflow,
+    InvalidArgument,
+    /// A text where a number was expected or the other way around
+    WrongType,
+    /// A reference outside of the sheet or one that could not be parsed
+    InvalidReference,
+    /// A lookup such as REGEXEXTRACT found nothing
+    NoMatch,
+    /// A circular reference still changing after the most iterations allowed
+    NotConverged,
+}
+
+/// An entry on the evaluation stack, references are only read when an
+/// operation needs their value
+#[derive(Debug, Clone)]
+pub enum Operand {
+    Value(Value),
+    Ref(Range),
+}
+
+/// State threaded through a single evaluation of a formula
+pub struct Context<'a> {
+    pub sheet: &'a Sheet,
+    /// The cell of the formula, relative references are resolved from here
+    pub pos: (usize, usize),
+    /// Seeded from the workbook seed, the recalculation and `pos` so the same
+    /// recalculation always draws the same numbers
+    pub rng: Rng,
+    /// Every cell and range looked at during the evaluation, including the
+    /// ones only known at run time through INDIRECT or OFFSET
+    pub reads: Vec<Range>,
+}
+
+impl<'a> Context<'a> {
+    pub fn new(sheet: &'a Sheet, pos: (usize, usize)) -> Self {
+        let rng = Rng::new(sheet.settings.seed)
+            .fork(sheet.generation())
+            .fork(pos.0 as u64)
+            .fork(pos.1 as u64);
+        Context {
+            sheet,
+            pos,
+            rng,
+            reads: vec![],
+        }
+    }
+
+    /// Reads the cell at `pos`, `None` if it is empty
+    fn read(&mut self, pos: (usize, usize)) -> Option<Result<Value, ExecutionError>> {
+        self.reads.push(Range::cell(pos));
+        self.sheet.get(pos).and_then(|c| c.result())
+    }
+
+    /// The value of `op`, references have to point at a single cell
+    pub fn value(&mut self, op: Operand) -> Result<Value, ExecutionError> {
+        match op {
+            Operand::Value(v) => Ok(v),
+            Operand::Ref(r) if r.is_cell() => {
+                self.read(r.from).ok_or(ExecutionError::CellNotFound)?
+            }
+            Operand::Ref(_) => Err(ExecutionError::WrongType),
+        }
+    }
+
+    pub fn number(&mut self, op: Operand) -> Result<Number, ExecutionError> {
+        self.value(op)?.number()
+    }
+
+    /// The values of all non empty cells in `range`
+    pub fn values(&mut self, range: Range) -> Vec<Result<Value, ExecutionError>> {
+        self.reads.push(range);
+        self.sheet
+            .cells_in(range)
+            .filter_map(|(_, c)| c.result())
+            .collect()
+    }
+
+    /// Like [Context::values], leaving out the rows hidden by the autofilter
+    /// and the results of SUBTOTAL
+    pub fn visible_values(&mut self, range: Range) -> Vec<Result<Value, ExecutionError>> {
+        self.reads.push(range);
+        let sheet = self.sheet;
+        sheet
+            .cells_in(range)
+            .filter_map(|(p, c)| match c {
+                _ if sheet.is_hidden(p.1) => None,
+                Cell::Expression(ex, _) if ex.calls(Function::Subtotal) => None,
+                c => c.result(),
+            })
+            .collect()
+    }
+}
+
+/// The state of one evaluation of [ByteCode]
+#[derive(Debug, Default)]
+struct Machine {
+    stack: Vec<Operand>,
+    /// Index of the next instruction
+    pc: usize,
+    /// Where to continue on an error and the stack height to go back to, for
+    /// every [Node::Catch] not yet ended
+    handlers: Vec<(usize, usize)>,
+}
+
+impl Machine {
+    fn pop(&mut self) -> Result<Operand, ExecutionError> {
+        self.stack.pop().ok_or(ExecutionError::OutOfStack)
+    }
+
+    /// Runs a single instruction, `pc` has already been moved past it
+    fn step(&mut self, n: &Node, ctx: &mut Context, cache: &Cache) -> Result<(), ExecutionError> {
+        match n {
+            Node::Call(f, argc) => {
+                let start = self
+                    .stack
+                    .len()
+                    .checked_sub(*argc)
+                    .ok_or(ExecutionError::OutOfStack)?;
+                let args = self.stack.split_off(start);
+                let res = f.call(args, ctx, cache)?;
+                self.stack.push(res);
+            }
+            Node::Sum(n) => {
+                let start = self
+                    .stack
+                    .len()
+                    .checked_sub(*n)
+                    .ok_or(ExecutionError::OutOfStack)?;
+                let big = ctx.sheet.settings.big_int;
+                let mut terms = self.stack.split_off(start).into_iter();
+                let first = terms.next().ok_or(ExecutionError::OutOfStack)?;
+                let mut sum = ctx.number(first)?;
+                for op in terms {
+                    sum = sum.add(&ctx.number(op)?, big)?;
+                }
+                self.stack.push(Operand::Value(Value::Number(sum)));
+            }
+            Node::Jump(target) => self.pc = *target,
+            Node::JumpIf(target) | Node::JumpIfNot(target) => {
+                let cond = ctx.value(self.pop()?)?.truthy()?;
+                if cond == matches!(n, Node::JumpIf(_)) {
+                    self.pc = *target;
+                }
+            }
+            Node::Catch(target) => self.handlers.push((*target, self.stack.len())),
+            Node::EndCatch => {
+                let v = ctx.value(self.pop()?)?;
+                self.handlers.pop();
+                self.stack.push(Operand::Value(v));
+            }
+            _ if n.is_op() => {
+                let v = n.calc(&mut self.stack, ctx)?;
+                self.stack.push(Operand::Value(v));
+            }
+            _ => self.stack.push(n.eval(ctx.pos)?),
+        }
+        Ok(())
+    }
+
+    /// Passes `e` to the innermost handler, fails if there is none
+    fn catch(&mut self, e: ExecutionError) -> Result<(), ExecutionError> {
+        let (target, height) = self.handlers.pop().ok_or(e)?;
+        self.stack.truncate(height);
+        self.pc = target;
+        Ok(())
+    }
+}
+
+/// A recorded evaluation of a formula, see [ByteCode::trace]
+#[derive(Debug, Clone)]
+pub struct Trace {
+    pub code: Vec<Node>,
+    pub steps: Vec<Step>,
+    pub result: Result<Value, ExecutionError>,
+}
+
+impl Trace {
+    /// The index of the step where the first error appeared, even if it was
+    /// caught later on
+    pub fn first_error(&self) -> Option<usize> {
+        self.steps.iter().position(|s| s.error.is_some())
+    }
+}
+
+/// The state after an instruction of a traced evaluation
+#[derive(Debug, Clone)]
+pub struct Step {
+    /// The index of the instruction in the code, `None` for reading the
+    /// result at the end
+    pub pc: Option<usize>,
+    /// The operand stack, with the top last
+    pub stack: Vec<Operand>,
+    /// Cells read by the instruction and what was found in them
+    pub reads: Vec<Read>,
+    /// The error the instruction failed with
+    pub error: Option<ExecutionError>,
+}
+
+/// A cell read during a [Step] and its value, `None` if it was empty
+pub type Read = ((usize, usize), Option<Result<Value, ExecutionError>>);
+
+impl Step {
+    /// The step after `ctx.reads` grew past `reads`. Of ranges only the
+    /// non-empty cells are listed.
+    fn new(
+        pc: Option<usize>,
+        stack: &[Operand],
+        ctx: &Context,
+        reads: usize,
+        error: Option<ExecutionError>,
+    ) -> Self {
+        Step {
+            pc,
+            stack: stack.to_vec(),
+            reads: ctx.reads[reads..]
+                .iter()
+                .flat_map(|r| match r.is_cell() {
+                    true => vec![r.from],
+                    false => ctx.sheet.cells_in(*r).map(|(p, _)| p).collect(),
+                })
+                .map(|p| (p, ctx.sheet.get(p).and_then(|c| c.result())))
+                .collect(),
+            error,
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
 pub struct ByteCode {
     code: Result<Vec<Node>, ()>,
+    /// The formula as parsed, before optimizing, to print it back as text
+    tree: Option<Expr>,
+    /// Kept for as long as the formula so repeated evaluations can reuse work
+    cache: Cache,
 }
 
 impl ByteCode {
-    pub fn execute(&self, sheet: &Sheet) -> Result<i64, ExecutionError> {
-        match &self.code {
-            Ok(expr) => {
-                let mut stack = vec![];
-
-                for n in expr {
-                    if let Node::Cell(x, y) = n {
-                        if let Some(c) = sheet[*x][*y].val() {
-                            stack.push(c);
-                        } else {
-                            return Err(ExecutionError::CellNotFound);
-                        }
-                    } else if n.is_op() {
-                        let v = n.calc(&mut stack)?;
-                        stack.push(v);
-                    } else {
-                        stack.push(n.eval()?)
-                    }
-                }
+    /// Evaluates the formula for the cell `ctx` was created for
+    pub fn execute(&self, ctx: &mut Context) -> Result<Value, ExecutionError> {
+        self.run(ctx, None)
+    }
 
-                stack.last().ok_or(ExecutionError::OutOfStack).copied()
+    /// Evaluates like [ByteCode::execute], recording the state after every
+    /// instruction
+    pub fn trace(&self, ctx: &mut Context) -> Trace {
+        let mut steps = vec![];
+        let result = self.run(ctx, Some(&mut steps));
+        Trace {
+            code: self.code.clone().unwrap_or_default(),
+            steps,
+            result,
+        }
+    }
+
+    fn run(
+        &self,
+        ctx: &mut Context,
+        mut trace: Option<&mut Vec<Step>>,
+    ) -> Result<Value, ExecutionError> {
+        let code = self
+            .code
+            .as_ref()
+            .map_err(|_| ExecutionError::CompilationError)?;
+        let mut machine = Machine::default();
+
+        while let Some(n) = code.get(machine.pc) {
+            let (pc, reads) = (machine.pc, ctx.reads.len());
+            machine.pc += 1;
+            let error = machine.step(n, ctx, &self.cache).err();
+            let caught = error.map_or(Ok(()), |e| machine.catch(e));
+            if let Some(steps) = trace.as_deref_mut() {
+                steps.push(Step::new(Some(pc), &machine.stack, ctx, reads, error));
             }
-            Err(e) => Err(ExecutionError::CompilationError),
+            caught?;
         }
+
+        let reads = ctx.reads.len();
+        let res = machine.pop().and_then(|top| ctx.value(top));
+        if let Some(steps) = trace {
+            steps.push(Step::new(
+                None,
+                &[],
+                ctx,
+                reads,
+                res.as_ref().err().copied(),
+            ));
+        }
+        res
     }
 
-    pub fn deps(&self, pos: (u16, u16)) -> Vec<(u16, u16)> {
+    /// The formula in canonical form as written in the cell at `pos`, without
+    /// the leading `=`. `None` if it could not be parsed.
+    pub fn print(&self, pos: (usize, usize)) -> Option<String> {
+        self.tree.as_ref().map(|e| printer::print(e, pos))
+    }
+
+    /// The formula as written in the cell at `to` after moving there from
+    /// `from` because of `shift`, with its references following the cells
+    /// they point at. `None` if it could not be parsed.
+    pub fn shifted(
+        &self,
+        from: (usize, usize),
+        to: (usize, usize),
+        shift: &impl Relocation,
+    ) -> Option<String> {
+        let tree = shift::rewrite(self.tree.clone()?, from, to, shift);
+        Some(printer::print(&tree, to))
+    }
+
+    /// The formula as written in the cell at `pos` after pasting it with rows
+    /// and columns swapped. `None` if it could not be parsed.
+    pub fn transposed(&self, pos: (usize, usize)) -> Option<String> {
+        let tree = shift::transpose(self.tree.clone()?);
+        Some(printer::print(&tree, pos))
+    }
+
+    /// Whether the formula calls `function`
+    pub fn calls(&self, function: Function) -> bool {
         match &self.code {
             Ok(expr) => expr
                 .iter()
-                .filter_map(|c| {
-                    if let Node::Cell(x, y) = c {
-                        Some((*x as u16, *y as u16))
-                    } else {
-                        None
-                    }
+                .any(|n| matches!(n, Node::Call(f, _) if *f == function)),
+            Err(_) => false,
+        }
+    }
+
+    /// Whether the formula calls a volatile function
+    pub fn is_volatile(&self) -> bool {
+        match &self.code {
+            Ok(expr) => expr
+                .iter()
+                .any(|n| matches!(n, Node::Call(f, _) if f.is_volatile())),
+            Err(_) => false,
+        }
+    }
+
+    pub fn deps(&self, pos: (usize, usize)) -> Vec<Range> {
+        match &self.code {
+            Ok(expr) => expr
+                .iter()
+                .flat_map(|c| match c {
+                    Node::Cell(r) => r.resolve(pos).map(Range::cell),
+                    Node::Range(a, b) => a
+                        .resolve(pos)
+                        .zip(b.resolve(pos))
+                        .map(|(a, b)| Range::new(a, b)),
+                    _ => None,
                 })
                 .collect(),
             Err(e) => vec![],
@@ -154,63 +745,311 @@ impl ByteCode {
     }
 }
 
+#[derive(Debug, PartialEq)]
+enum Token {
+    Val(Value),
+    /// Relative to the cell the formula is written in
+    Cell(CellRef),
+    /// A function name
+    Ident(String),
+    /// One of the operator [Node]s
+    Op(Node),
+    LParen,
+    RParen,
+    Comma,
+    Colon,
+    Percent,
+    Err,
+}
+
 #[derive(Debug)]
 struct Lexer<'a> {
     s: &'a str,
+    /// The cell the formula is written in
+    pos: (usize, usize),
 }
 
 impl<'a> Lexer<'a> {
-    pub fn new(s: &'a str) -> Self {
-        Lexer { s }
+    pub fn new(s: &'a str, pos: (usize, usize)) -> Self {
+        Lexer { s, pos }
     }
-}
 
-static LETTERS: [char; 26] = [
-    'A', 'B', 'C', 'D', 'E', 'F', 'G', 'H', 'I', 'J', 'K', 'L', 'M', 'N', 'O', 'P', 'Q', 'R', 'S',
-    'T', 'U', 'V', 'W', 'X', 'Y', 'Z',
-];
+    /// Splits off a number literal such as `12`, `1_000`, `0.5` or `1.2e-3`
+    fn number(&mut self) -> &'a str {
+        let s = self.s;
+        let mut len = s
+            .find(|c: char| !(c.is_ascii_digit() || c == '.' || c == '_'))
+            .unwrap_or(s.len());
+
+        let exp = &s[len..];
+        if exp.starts_with(['e', 'E']) {
+            let sign = exp[1..].starts_with(['+', '-']) as usize;
+            let digits = exp[1 + sign..]
+                .find(|c: char| !c.is_ascii_digit())
+                .unwrap_or(exp.len() - 1 - sign);
+            if digits > 0 {
+                len += 1 + sign + digits;
+            }
+        }
+
+        self.s = &s[len..];
+        &s[..len]
+    }
+
+    /// Lexes a text literal, a quote inside it is written twice as in `"a""b"`
+    fn text(&mut self) -> Token {
+        let mut text = String::new();
+        let mut chars = self.s.char_indices().skip(1).peekable();
+
+        while let Some((i, ch)) = chars.next() {
+            if ch == '"' {
+                if let Some((_, '"')) = chars.peek() {
+                    chars.next();
+                } else {
+                    self.s = &self.s[i + 1..];
+                    return Token::Val(Value::Text(text));
+                }
+            }
+            text.push(ch);
+        }
+
+        self.s = "";
+        Token::Err
+    }
+
+    /// Splits off the longest prefix of characters matching `f`
+    fn take_while(&mut self, f: impl Fn(char) -> bool) -> &'a str {
+        let len = self.s.find(|c| !f(c)).unwrap_or(self.s.len());
+        let (taken, rest) = self.s.split_at(len);
+        self.s = rest;
+        taken
+    }
+}
 
 impl<'a> Iterator for Lexer<'a> {
-    type Item = Node;
+    type Item = Token;
 
     fn next(&mut self) -> Option<Self::Item> {
-        use Node::*;
+        use Token::*;
 
         self.s = self.s.trim_start();
 
         let c = self.s.chars().next();
         if let Some(ch) = c {
             if ch.is_ascii_digit() {
-                let mut len = self.s.len();
-                for (i, ch) in self.s.chars().enumerate() {
-                    if !ch.is_ascii_digit() {
-                        len = i;
-                        break;
-                    }
+                let num = self.number();
+                return Some(Number::from_str(num).map_or(Err, |n| Val(Value::Number(n))));
+            } else if ch == '"' {
+                return Some(self.text());
+            } else if self
+                .s
+                .get(..INVALID_REFERENCE.len())
+                .is_some_and(|s| s.eq_ignore_ascii_case(INVALID_REFERENCE))
+            {
+                self.s = &self.s[INVALID_REFERENCE.len()..];
+                return Some(Cell(CellRef::INVALID));
+            } else if ch.is_ascii_alphabetic() || ch == '$' {
+                let abs_x = self.take_while(|c| c == '$').len() == 1;
+                let name = self
+                    .take_while(|c| c.is_ascii_alphabetic())
+                    .to_ascii_uppercase();
+                let abs_y = self.take_while(|c| c == '$').len() == 1;
+                let row = self.take_while(|c| c.is_ascii_digit());
+                if row.is_empty() && !abs_x && !abs_y {
+                    return Some(match name.as_str() {
+                        "TRUE" => Val(Value::Bool(true)),
+                        "FALSE" => Val(Value::Bool(false)),
+                        _ => Ident(name),
+                    });
+                }
+                return Some(
+                    match (reference::column_index(&name), row.parse::<usize>()) {
+                        (Some(x), Ok(r)) if r > 0 && r <= ROWS => {
+                            Cell(CellRef::new((x, r - 1), (abs_x, abs_y), self.pos))
+                        }
+                        _ => Err,
+                    },
+                );
+            }
+
+            self.s = &self.s[ch.len_utf8()..];
+            Some(match ch {
+                '+' => Op(Node::Add),
+                '-' => Op(Node::Sub),
+                '*' => Op(Node::Mul),
+                '/' => Op(Node::Div),
+                '(' => LParen,
+                ')' => RParen,
+                ',' => Comma,
+                ':' => Colon,
+                '%' => Percent,
+                '=' => Op(Node::Eq),
+                '<' | '>' => {
+                    let (op, len) = match (ch, self.s.chars().next()) {
+                        ('<', Some('=')) => (Node::Le, 1),
+                        ('<', Some('>')) => (Node::Ne, 1),
+                        ('>', Some('=')) => (Node::Ge, 1),
+                        ('<', _) => (Node::Lt, 0),
+                        _ => (Node::Gt, 0),
+                    };
+                    self.s = &self.s[len..];
+                    Op(op)
                 }
-                let (num, rest) = self.s.split_at(len);
-                self.s = rest;
-                return Some(num.parse().map_or(Err, |x| Val(x)));
-            } else if ch == '+' {
-                self.s = &self.s[1..];
-                return Some(Add);
-            } else if ch == '-' {
-                self.s = &self.s[1..];
-                return Some(Sub);
-            } else if ch == '*' {
-                self.s = &self.s[1..];
-                return Some(Mul);
-            } else if ch == '/' {
-                self.s = &self.s[1..];
-                return Some(Div);
-            } else if let Some(index) = LETTERS.iter().position(|x| *x == ch) {
-                let row = self.s.chars().nth(1).unwrap().to_digit(10).unwrap();
-                self.s = &self.s[2..];
-                return Some(Cell(index, row as usize - 1));
-            }
-            Some(Err)
+                _ => Err,
+            })
         } else {
             None
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn result(sheet: &Sheet, pos: (usize, usize)) -> Result<Value, ExecutionError> {
+        sheet.get(pos).unwrap().result().unwrap()
+    }
+
+    #[test]
+    fn boolean_literals() {
+        let mut sheet = Sheet::new();
+        sheet.enter((0, 0), "=TRUE");
+        sheet.enter((0, 1), "=if(false,1,2)");
+        assert_eq!(result(&sheet, (0, 0)), Ok(Value::Bool(true)));
+        assert_eq!(result(&sheet, (0, 1)), Ok(Value::Number(Number::Int(2))));
+        assert_eq!(sheet.entry((0, 1)), "=IF(FALSE,1,2)");
+    }
+
+    #[test]
+    fn and_or_over_ranges() {
+        let mut sheet = Sheet::new();
+        for (y, v) in ["1", "2", "0", "text"].iter().enumerate() {
+            sheet.enter((0, y), v);
+        }
+        let cases = [
+            ("=AND(A1:A2)", Ok(Value::Bool(true))),
+            ("=AND(A1:A4)", Ok(Value::Bool(false))),
+            ("=OR(A3,A1:A2)", Ok(Value::Bool(true))),
+            ("=OR(A3:A4)", Ok(Value::Bool(false))),
+            ("=AND(INDIRECT(\"A1:A2\"),TRUE)", Ok(Value::Bool(true))),
+            // Ranges without any value, text or not, have nothing to decide
+            ("=AND(B1:B3)", Err(ExecutionError::WrongType)),
+            ("=OR(A4:A5)", Err(ExecutionError::WrongType)),
+        ];
+        for (i, (formula, expected)) in cases.into_iter().enumerate() {
+            sheet.enter((1, i + 10), formula);
+            assert_eq!(result(&sheet, (1, i + 10)), expected, "{}", formula);
+        }
+    }
+
+    #[test]
+    fn number_literals() {
+        let mut sheet = Sheet::new();
+        let cases = [
+            ("=50%*4", "2"),
+            ("=1.5e3+1", "1501"),
+            ("=2E-2", "0.02"),
+            ("=1_000*2", "2000"),
+        ];
+        for (y, (formula, expected)) in cases.into_iter().enumerate() {
+            sheet.enter((0, y), formula);
+            let value = sheet.get((0, y)).unwrap().val().unwrap();
+            assert!(
+                value.compare(&expected.parse().unwrap()).is_eq(),
+                "{} gave {}",
+                formula,
+                value
+            );
+        }
+    }
+
+    #[test]
+    fn short_circuits() {
+        let mut sheet = Sheet::new();
+        let cases = [
+            ("=IF(1,2,1/0)", Ok(Value::Number(Number::Int(2)))),
+            ("=IF(0,1/0)", Ok(Value::Bool(false))),
+            ("=IF(1/0,1,2)", Err(ExecutionError::DivByZero)),
+            ("=IFERROR(1/0,7)", Ok(Value::Number(Number::Int(7)))),
+            ("=1+IFERROR(2,1/0)", Ok(Value::Number(Number::Int(3)))),
+            (
+                "=IFERROR(IFERROR(1/0,1/0),5)*2",
+                Ok(Value::Number(Number::Int(10))),
+            ),
+            ("=AND(FALSE,1/0)", Ok(Value::Bool(false))),
+            ("=OR(TRUE,1/0)", Ok(Value::Bool(true))),
+            ("=AND(TRUE,1/0)", Err(ExecutionError::DivByZero)),
+        ];
+        for (y, (formula, expected)) in cases.into_iter().enumerate() {
+            sheet.enter((0, y), formula);
+            assert_eq!(result(&sheet, (0, y)), expected, "{}", formula);
+        }
+    }
+
+    #[test]
+    fn shares_formulas_the_same_apart_from_where_they_are() {
+        let mut formulas = FormulaCache::default();
+        let b1 = formulas.compile("A1*2", (1, 0));
+        assert!(Rc::ptr_eq(&b1, &formulas.compile("A2*2", (1, 1))));
+        assert!(Rc::ptr_eq(&b1, &formulas.compile("a1 * 2", (1, 0))));
+        assert!(!Rc::ptr_eq(&b1, &formulas.compile("A1*2", (1, 1))));
+        // Absolute references point at the same cell from everywhere
+        let c1 = formulas.compile("$A$1*2", (2, 0));
+        assert!(Rc::ptr_eq(&c1, &formulas.compile("$A$1*2", (5, 9))));
+        // Equal values written differently are printed differently
+        let one = formulas.compile("1.0", (0, 0));
+        assert!(!Rc::ptr_eq(&one, &formulas.compile("1.00", (0, 0))));
+    }
+
+    #[test]
+    fn copies_keep_relative_references() {
+        let mut sheet = Sheet::new();
+        sheet.enter((0, 0), "2");
+        sheet.enter((0, 1), "3");
+        sheet.enter((1, 0), "=A1*$A$1");
+        let Some(Cell::Expression(ex, _)) = sheet.get((1, 0)).cloned() else {
+            panic!("not a formula");
+        };
+        let copy = ex.copied((1, 1), &mut sheet.formulas);
+        assert_eq!(copy.to_string(), "=A2*$A$1");
+        sheet.enter((1, 1), &copy.to_string());
+        assert_eq!(sheet.shown((1, 1)), "6");
+    }
+
+    #[test]
+    fn traces_record_every_step_and_read() {
+        let mut sheet = Sheet::new();
+        sheet.enter((0, 0), "1");
+        sheet.enter((1, 1), "2");
+        sheet.enter((2, 0), "=A1+SUM(B1:B3)");
+        let trace = sheet.trace((2, 0)).unwrap();
+        assert_eq!(trace.result, Ok(Value::Number(Number::Int(3))));
+        assert_eq!(trace.first_error(), None);
+        // One step per instruction and one for reading the result
+        assert_eq!(trace.steps.len(), trace.code.len() + 1);
+        assert_eq!(trace.steps.last().unwrap().pc, None);
+
+        let mut reads: Vec<Read> = trace.steps.iter().flat_map(|s| s.reads.clone()).collect();
+        reads.sort_by_key(|(p, _)| *p);
+        // Of the range only the cell with a value
+        assert_eq!(
+            reads,
+            [
+                ((0, 0), Some(Ok(Value::Number(Number::Int(1))))),
+                ((1, 1), Some(Ok(Value::Number(Number::Int(2))))),
+            ]
+        );
+        assert!(sheet.trace((0, 0)).is_none());
+    }
+
+    #[test]
+    fn traces_keep_caught_errors() {
+        let mut sheet = Sheet::new();
+        sheet.enter((0, 0), "=IFERROR(1/0,5)");
+        let trace = sheet.trace((0, 0)).unwrap();
+        assert_eq!(trace.result, Ok(Value::Number(Number::Int(5))));
+        let first = trace.first_error().unwrap();
+        assert_eq!(trace.steps[first].error, Some(ExecutionError::DivByZero));
+    }
+}
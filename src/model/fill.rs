@@ -0,0 +1,470 @@
+//! Extending the cells at the start of a range over the rest of it, like
+//! dragging the fill handle
+
+use std::rc::Rc;
+
+use super::{
+    number::Number,
+    reference::Range,
+    sheet::{Cell, Sheet},
+};
+
+const DAYS: [&str; 7] = [
+    "Monday",
+    "Tuesday",
+    "Wednesday",
+    "Thursday",
+    "Friday",
+    "Saturday",
+    "Sunday",
+];
+const MONTHS: [&str; 12] = [
+    "January",
+    "February",
+    "March",
+    "April",
+    "May",
+    "June",
+    "July",
+    "August",
+    "September",
+    "October",
+    "November",
+    "December",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FillDirection {
+    /// Each column from the cells at its top
+    Down,
+    /// Each row from the cells at its left
+    Right,
+}
+
+/// A sequence recognised in the cells a line starts with
+#[derive(Debug, Clone, PartialEq)]
+enum Series {
+    /// Numbers going up by the average step between them
+    Linear { last: Number, step: Number },
+    /// Names of days or months, going on by the step between the last two
+    Names {
+        names: &'static [&'static str],
+        last: usize,
+        step: usize,
+        style: Style,
+    },
+    /// Dates written as `2024-01-31`, going on by the days or the months
+    /// between the last two. Dates on the same day of the month or all on
+    /// the last day of their month go on by months.
+    Dates { last: Date, step: DateStep },
+    /// Anything else is repeated, formulas keep pointing at cells at the
+    /// same distance
+    Copy,
+}
+
+/// How a name is written
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Style {
+    short: bool,
+    case: Case,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Case {
+    Lower,
+    Upper,
+    Title,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum DateStep {
+    Days(i64),
+    Months(i64),
+    /// Months, landing on the last day of each
+    MonthEnds(i64),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Date {
+    year: i64,
+    month: u32,
+    day: u32,
+}
+
+/// Fills every column or row of `range`. The non-empty cells it starts with
+/// are the seeds, the cells after them are overwritten with the series they
+/// make. Returns false if there was nothing to fill.
+pub fn fill(sheet: &mut Sheet, range: Range, direction: FillDirection) -> bool {
+    let lines: Vec<Vec<(usize, usize)>> = match direction {
+        FillDirection::Down => (range.from.0..=range.to.0)
+            .map(|x| (range.from.1..=range.to.1).map(|y| (x, y)).collect())
+            .collect(),
+        FillDirection::Right => (range.from.1..=range.to.1)
+            .map(|y| (range.from.0..=range.to.0).map(|x| (x, y)).collect())
+            .collect(),
+    };
+
+    let mut filled = vec![];
+    for line in lines {
+        let seeds: Vec<((usize, usize), Cell)> = line
+            .iter()
+            .map_while(|p| match sheet.get(*p) {
+                Some(Cell::None) | None => None,
+                Some(c) => Some((*p, c.clone())),
+            })
+            .collect();
+        if seeds.is_empty() || seeds.len() == line.len() {
+            continue;
+        }
+
+        let cells: Vec<Cell> = seeds.iter().map(|(_, c)| c.clone()).collect();
+        let mut series = Series::new(&cells, sheet.settings.big_int);
+        for (i, &to) in line[seeds.len()..].iter().enumerate() {
+            let cell = match series.next(sheet.settings.big_int) {
+                Some(cell) => cell,
+                None => match &seeds[i % seeds.len()] {
+                    (_, Cell::Expression(ex, r)) => {
+                        Cell::Expression(Rc::new(ex.copied(to, &mut sheet.formulas)), r.clone())
+                    }
+                    (_, cell) => cell.clone(),
+                },
+            };
+            filled.push((to, cell));
+        }
+    }
+    if filled.is_empty() {
+        return false;
+    }
+    sheet.insert_cells(filled);
+    true
+}
+
+impl Series {
+    fn new(seeds: &[Cell], big: bool) -> Self {
+        if let Some(numbers) = seeds
+            .iter()
+            .map(|c| match c {
+                Cell::Val(n) => Some(n.clone()),
+                _ => None,
+            })
+            .collect::<Option<Vec<_>>>()
+        {
+            return Series::linear(&numbers, big).unwrap_or(Series::Copy);
+        }
+
+        let texts: Option<Vec<&str>> = seeds
+            .iter()
+            .map(|c| match c {
+                Cell::String(s) => Some(s.as_str()),
+                _ => None,
+            })
+            .collect();
+        let Some(texts) = texts else {
+            return Series::Copy;
+        };
+
+        for names in [&DAYS[..], &MONTHS[..]] {
+            if let Some(series) = Series::names(names, &texts) {
+                return series;
+            }
+        }
+        Series::dates(&texts).unwrap_or(Series::Copy)
+    }
+
+    /// A single number is repeated as it is
+    fn linear(numbers: &[Number], big: bool) -> Option<Self> {
+        let (first, last) = (numbers.first()?, numbers.last()?);
+        if numbers.len() < 2 {
+            return None;
+        }
+        let steps = Number::Int(numbers.len() as i64 - 1);
+        let step = last.sub(first, big).and_then(|d| d.div(&steps, big)).ok()?;
+        Some(Series::Linear {
+            last: last.clone(),
+            step,
+        })
+    }
+
+    fn names(names: &'static [&'static str], texts: &[&str]) -> Option<Self> {
+        let found: Vec<(usize, Style)> = texts
+            .iter()
+            .map(|t| find_name(names, t))
+            .collect::<Option<_>>()?;
+        let (last, style) = *found.last()?;
+        let step = match found.len() {
+            1 => 1,
+            n => (found[n - 1].0 + names.len() - found[n - 2].0) % names.len(),
+        };
+        Some(Series::Names {
+            names,
+            last,
+            step,
+            style,
+        })
+    }
+
+    fn dates(texts: &[&str]) -> Option<Self> {
+        let dates: Vec<Date> = texts
+            .iter()
+            .map(|t| Date::parse(t))
+            .collect::<Option<_>>()?;
+        let last = *dates.last()?;
+        let step = match dates.len() {
+            1 => DateStep::Days(1),
+            n => {
+                let prev = dates[n - 2];
+                let months = (last.year - prev.year) * 12 + last.month as i64 - prev.month as i64;
+                if months != 0 && dates.iter().all(|d| d.day == last.day) {
+                    DateStep::Months(months)
+                } else if months != 0 && dates.iter().all(Date::is_month_end) {
+                    DateStep::MonthEnds(months)
+                } else {
+                    DateStep::Days(last.days() - prev.days())
+                }
+            }
+        };
+        Some(Series::Dates { last, step })
+    }
+
+    /// The next cell of the series, `None` if the seeds are to be repeated
+    fn next(&mut self, big: bool) -> Option<Cell> {
+        match self {
+            Series::Linear { last, step } => {
+                *last = last.add(step, big).ok()?;
+                Some(Cell::Val(last.clone()))
+            }
+            Series::Names {
+                names,
+                last,
+                step,
+                style,
+            } => {
+                *last = (*last + *step) % names.len();
+                Some(Cell::String(style.write(names[*last])))
+            }
+            Series::Dates { last, step } => {
+                *last = match *step {
+                    DateStep::Days(days) => Date::from_days(last.days() + days),
+                    DateStep::Months(months) => last.add_months(months),
+                    DateStep::MonthEnds(months) => {
+                        let date = last.add_months(months);
+                        Date {
+                            day: days_in_month(date.year, date.month),
+                            ..date
+                        }
+                    }
+                };
+                Some(Cell::String(last.to_string()))
+            }
+            Series::Copy => None,
+        }
+    }
+}
+
+/// The index of the day or month `text` names in full or by its first three
+/// letters, with the way it is written
+fn find_name(names: &[&str], text: &str) -> Option<(usize, Style)> {
+    let i = names.iter().position(|n| {
+        n.eq_ignore_ascii_case(text) || (text.len() == 3 && n[..3].eq_ignore_ascii_case(text))
+    })?;
+    let case = if text.chars().all(|c| c.is_ascii_lowercase()) {
+        Case::Lower
+    } else if text.len() > 1 && text.chars().all(|c| c.is_ascii_uppercase()) {
+        Case::Upper
+    } else {
+        Case::Title
+    };
+    Some((
+        i,
+        Style {
+            short: text.len() == 3,
+            case,
+        },
+    ))
+}
+
+impl Style {
+    fn write(&self, name: &str) -> String {
+        let name = if self.short { &name[..3] } else { name };
+        match self.case {
+            Case::Lower => name.to_ascii_lowercase(),
+            Case::Upper => name.to_ascii_uppercase(),
+            Case::Title => name.to_owned(),
+        }
+    }
+}
+
+impl Date {
+    /// Reads `2024-01-31`
+    fn parse(s: &str) -> Option<Date> {
+        let mut parts = s.split('-');
+        let (year, month, day) = (parts.next()?, parts.next()?, parts.next()?);
+        if parts.next().is_some() || year.len() != 4 || month.len() != 2 || day.len() != 2 {
+            return None;
+        }
+        let date = Date {
+            year: year.parse().ok()?,
+            month: month.parse().ok()?,
+            day: day.parse().ok()?,
+        };
+        ((1..=12).contains(&date.month)
+            && (1..=days_in_month(date.year, date.month)).contains(&date.day))
+        .then_some(date)
+    }
+
+    fn is_month_end(&self) -> bool {
+        self.day == days_in_month(self.year, self.month)
+    }
+
+    /// Days since 1970-01-01
+    fn days(&self) -> i64 {
+        let y = if self.month <= 2 {
+            self.year - 1
+        } else {
+            self.year
+        };
+        let era = y.div_euclid(400);
+        let yoe = y - era * 400;
+        let mp = (self.month as i64 + 9) % 12;
+        let doy = (153 * mp + 2) / 5 + self.day as i64 - 1;
+        let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+        era * 146097 + doe - 719468
+    }
+
+    fn from_days(days: i64) -> Date {
+        let z = days + 719468;
+        let era = z.div_euclid(146097);
+        let doe = z - era * 146097;
+        let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+        let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+        let mp = (5 * doy + 2) / 153;
+        let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+        let year = yoe + era * 400 + (month <= 2) as i64;
+        Date { year, month, day }
+    }
+
+    /// The same day `months` later, or the last day of that month if it is
+    /// shorter
+    fn add_months(&self, months: i64) -> Date {
+        let index = self.year * 12 + self.month as i64 - 1 + months;
+        let (year, month) = (index.div_euclid(12), (index.rem_euclid(12) + 1) as u32);
+        Date {
+            year,
+            month,
+            day: self.day.min(days_in_month(year, month)),
+        }
+    }
+}
+
+impl std::fmt::Display for Date {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:04}-{:02}-{:02}", self.year, self.month, self.day)
+    }
+}
+
+fn days_in_month(year: i64, month: u32) -> u32 {
+    match month {
+        2 if year % 4 == 0 && (year % 100 != 0 || year % 400 == 0) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Fills the column starting with `seeds` down to `len` cells
+    fn filled(seeds: &[&str], len: usize) -> Vec<String> {
+        let mut sheet = Sheet::new();
+        for (y, s) in seeds.iter().enumerate() {
+            sheet.enter((0, y), s);
+        }
+        assert!(fill(
+            &mut sheet,
+            Range::new((0, 0), (0, len - 1)),
+            FillDirection::Down
+        ));
+        (seeds.len()..len).map(|y| sheet.entry((0, y))).collect()
+    }
+
+    #[test]
+    fn numbers_go_on_by_exact_steps() {
+        assert_eq!(filled(&["1", "3"], 4), ["5", "7"]);
+        assert_eq!(filled(&["1", "2", "7"], 5), ["10", "13"]);
+        assert_eq!(filled(&["0.1", "0.2"], 4), ["0.3", "0.4"]);
+        assert_eq!(filled(&["7"], 3), ["7", "7"]);
+    }
+
+    #[test]
+    fn names_of_days_and_months() {
+        assert_eq!(filled(&["Mon", "Wed"], 4), ["Fri", "Sun"]);
+        assert_eq!(filled(&["JANUARY"], 2), ["FEBRUARY"]);
+        assert_eq!(filled(&["dec"], 3), ["jan", "feb"]);
+        assert_eq!(filled(&["sunday"], 2), ["monday"]);
+    }
+
+    #[test]
+    fn dates_by_days_and_months() {
+        assert_eq!(
+            filled(&["2024-02-27", "2024-02-28"], 4),
+            ["2024-02-29", "2024-03-01"]
+        );
+        assert_eq!(
+            filled(&["2024-01-15", "2024-03-15"], 4),
+            ["2024-05-15", "2024-07-15"]
+        );
+        assert_eq!(filled(&["2023-12-31"], 2), ["2024-01-01"]);
+    }
+
+    #[test]
+    fn month_ends_stay_month_ends() {
+        assert_eq!(
+            filled(&["2024-01-31", "2024-02-29"], 5),
+            ["2024-03-31", "2024-04-30", "2024-05-31"]
+        );
+        // The same day of the month is kept where it exists
+        assert_eq!(
+            filled(&["2024-01-30", "2024-03-30"], 4),
+            ["2024-05-30", "2024-07-30"]
+        );
+        assert_eq!(filled(&["2023-11-30", "2023-12-30"], 3), ["2024-01-30"]);
+    }
+
+    #[test]
+    fn anything_else_is_repeated() {
+        assert_eq!(filled(&["a", "b"], 5), ["a", "b", "a"]);
+        assert_eq!(filled(&["1", "b"], 3), ["1"]);
+        assert_eq!(filled(&["2024-13-01"], 2), ["2024-13-01"]);
+        assert_eq!(filled(&["=A1+1"], 3), ["=A2+1", "=A3+1"]);
+    }
+
+    #[test]
+    fn filling_right_line_by_line() {
+        let mut sheet = Sheet::new();
+        sheet.enter((0, 0), "1");
+        sheet.enter((1, 0), "2");
+        sheet.enter((0, 1), "Jan");
+        sheet.enter((0, 2), "=A1*2");
+        let range = Range::new((0, 0), (3, 3));
+        assert!(fill(&mut sheet, range, FillDirection::Right));
+        assert_eq!(sheet.entry((3, 0)), "4");
+        assert_eq!(sheet.entry((3, 1)), "Apr");
+        assert_eq!(sheet.entry((1, 2)), "=B1*2");
+        assert_eq!(sheet.shown((1, 2)), "4");
+        // Empty lines and lines without room are left alone
+        assert!(matches!(sheet.get((1, 3)), Some(Cell::None)));
+        assert!(!fill(
+            &mut sheet,
+            Range::new((0, 5), (3, 5)),
+            FillDirection::Right
+        ));
+        assert!(!fill(
+            &mut sheet,
+            Range::new((0, 0), (3, 0)),
+            FillDirection::Right
+        ));
+    }
+}
@@ -0,0 +1,268 @@
+//! Autofilters, showing only the rows of a table matching the criteria of
+//! its filtered columns. Rows are hidden from view, never deleted.
+
+use std::collections::{BTreeMap, HashSet};
+
+use super::{
+    number::Number,
+    reference::Range,
+    sheet::{Cell, Sheet},
+    shift::{Axis, Relocation, Shift},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Comparison {
+    Less,
+    LessOrEqual,
+    Greater,
+    GreaterOrEqual,
+    Equal,
+    NotEqual,
+}
+
+/// What the value in a filtered column has to be for its row to be shown
+#[derive(Debug, Clone, PartialEq)]
+pub enum Criterion {
+    /// Shown as one of these texts, `""` for empty cells
+    Values(Vec<String>),
+    /// A number comparing to this one
+    Compare(Comparison, Number),
+    /// Text containing this, regardless of case
+    Contains(String),
+    /// One of the `count` largest numbers of the column, or the smallest
+    /// with `bottom`. Rows tying with the last one are shown too.
+    Top { count: usize, bottom: bool },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct AutoFilter {
+    /// The header row and the rows below it that are filtered
+    pub range: Range,
+    /// The criteria of the filtered columns
+    pub criteria: BTreeMap<usize, Criterion>,
+}
+
+impl AutoFilter {
+    /// A filter on `range` with its first row as the header, showing every
+    /// row until criteria are added
+    pub fn new(range: Range) -> Self {
+        AutoFilter {
+            range,
+            criteria: BTreeMap::new(),
+        }
+    }
+
+    /// The rows below the header not matching every criterion
+    pub fn hidden(&self, sheet: &Sheet) -> HashSet<usize> {
+        let rows = self.range.from.1 + 1..=self.range.to.1;
+        let mut hidden = HashSet::new();
+        for (&x, criterion) in &self.criteria {
+            // The last number making the cut of a top N
+            let last = match criterion {
+                &Criterion::Top { count, bottom } => {
+                    let mut numbers: Vec<Number> =
+                        rows.clone().filter_map(|y| number(sheet, (x, y))).collect();
+                    numbers.sort_by(|a, b| match bottom {
+                        false => b.compare(a),
+                        true => a.compare(b),
+                    });
+                    numbers.truncate(count);
+                    numbers.pop()
+                }
+                _ => None,
+            };
+            hidden.extend(
+                rows.clone()
+                    .filter(|y| !criterion.matches(sheet, (x, *y), last.as_ref())),
+            );
+        }
+        hidden
+    }
+
+    /// The filter after rows or columns are inserted or deleted, `None` if
+    /// its header row is deleted
+    pub fn shifted(&self, shift: &Shift) -> Option<Self> {
+        shift.pos(self.range.from)?;
+        let criteria = match shift.axis {
+            Axis::Column => self
+                .criteria
+                .iter()
+                .filter_map(|(x, c)| Some((shift.index(*x)?, c.clone())))
+                .collect(),
+            Axis::Row => self.criteria.clone(),
+        };
+        Some(AutoFilter {
+            range: shift.range(self.range)?,
+            criteria,
+        })
+    }
+}
+
+impl Criterion {
+    /// Whether the cell at `pos` passes, `last` is the last number making
+    /// the cut of a [Criterion::Top]
+    fn matches(&self, sheet: &Sheet, pos: (usize, usize), last: Option<&Number>) -> bool {
+        // Empty cells are shown as `---`, they are matched as `""`
+        let text = || match sheet.get(pos) {
+            Some(Cell::None) | None => String::new(),
+            Some(_) => {
+                let mut text = String::new();
+                sheet.display(pos, &mut text).unwrap();
+                text.trim().to_owned()
+            }
+        };
+        match self {
+            Criterion::Values(values) => {
+                let text = text();
+                values.iter().any(|v| v.eq_ignore_ascii_case(&text))
+            }
+            Criterion::Compare(op, n) => number(sheet, pos).is_some_and(|v| {
+                let order = v.compare(n);
+                match op {
+                    Comparison::Less => order.is_lt(),
+                    Comparison::LessOrEqual => order.is_le(),
+                    Comparison::Greater => order.is_gt(),
+                    Comparison::GreaterOrEqual => order.is_ge(),
+                    Comparison::Equal => order.is_eq(),
+                    Comparison::NotEqual => order.is_ne(),
+                }
+            }),
+            Criterion::Contains(part) => text().to_lowercase().contains(&part.to_lowercase()),
+            Criterion::Top { bottom, .. } => match (number(sheet, pos), last) {
+                (Some(v), Some(last)) if *bottom => v.compare(last).is_le(),
+                (Some(v), Some(last)) => v.compare(last).is_ge(),
+                _ => false,
+            },
+        }
+    }
+}
+
+/// The number in a cell or computed by its formula
+fn number(sheet: &Sheet, pos: (usize, usize)) -> Option<Number> {
+    sheet.get(pos).and_then(Cell::val)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A table of names in column A under a header, with row 3 left blank
+    fn table() -> Sheet {
+        let mut sheet = Sheet::new();
+        for (y, name) in [(0, "Name"), (1, "Rome"), (3, "Paris"), (4, "rome")] {
+            sheet.insert_cell(Cell::String(name.to_owned()), (0, y));
+        }
+        sheet
+    }
+
+    fn hidden(sheet: &Sheet, criterion: Criterion) -> Vec<usize> {
+        let mut filter = AutoFilter::new(Range::new((0, 0), (0, 4)));
+        filter.criteria.insert(0, criterion);
+        let mut rows: Vec<usize> = filter.hidden(sheet).into_iter().collect();
+        rows.sort();
+        rows
+    }
+
+    #[test]
+    fn values_match_blank_rows_as_empty_text() {
+        let sheet = table();
+        assert_eq!(
+            hidden(&sheet, Criterion::Values(vec!["".to_owned()])),
+            vec![1, 3, 4]
+        );
+        assert_eq!(
+            hidden(&sheet, Criterion::Values(vec!["ROME".to_owned()])),
+            vec![2, 3]
+        );
+        assert_eq!(
+            hidden(
+                &sheet,
+                Criterion::Values(vec!["rome".to_owned(), "".to_owned()])
+            ),
+            vec![3]
+        );
+    }
+
+    #[test]
+    fn contains_skips_blank_rows() {
+        let sheet = table();
+        assert_eq!(
+            hidden(&sheet, Criterion::Contains("-".to_owned())),
+            vec![1, 2, 3, 4]
+        );
+        assert_eq!(hidden(&sheet, Criterion::Contains("r".to_owned())), vec![2]);
+    }
+
+    /// A column of numbers under a header, with a tie and a text
+    fn numbers() -> Sheet {
+        let mut sheet = Sheet::new();
+        for (y, s) in ["Amount", "5", "20", "x", "10", "20"].iter().enumerate() {
+            sheet.enter((0, y), s);
+        }
+        sheet
+    }
+
+    fn hidden_numbers(criterion: Criterion) -> Vec<usize> {
+        let mut filter = AutoFilter::new(Range::new((0, 0), (0, 5)));
+        filter.criteria.insert(0, criterion);
+        let mut rows: Vec<usize> = filter.hidden(&numbers()).into_iter().collect();
+        rows.sort();
+        rows
+    }
+
+    #[test]
+    fn top_and_bottom_keep_ties() {
+        let top = |count, bottom| hidden_numbers(Criterion::Top { count, bottom });
+        assert_eq!(top(1, false), vec![1, 3, 4]);
+        assert_eq!(top(3, false), vec![1, 3]);
+        assert_eq!(top(2, true), vec![2, 3, 5]);
+        assert_eq!(top(10, true), vec![3]);
+    }
+
+    #[test]
+    fn comparing_numbers_skips_text() {
+        let compare = |op, n| hidden_numbers(Criterion::Compare(op, Number::Int(n)));
+        assert_eq!(compare(Comparison::Greater, 5), vec![1, 3]);
+        assert_eq!(compare(Comparison::LessOrEqual, 10), vec![2, 3, 5]);
+        assert_eq!(compare(Comparison::Equal, 20), vec![1, 3, 4]);
+        assert_eq!(compare(Comparison::NotEqual, 20), vec![2, 3, 5]);
+    }
+
+    #[test]
+    fn filters_follow_shifts() {
+        let mut filter = AutoFilter::new(Range::new((1, 2), (3, 6)));
+        filter
+            .criteria
+            .insert(3, Criterion::Contains("a".to_owned()));
+
+        let shifted = filter.shifted(&Shift::insert(Axis::Row, 4, 2)).unwrap();
+        assert_eq!(shifted.range, Range::new((1, 2), (3, 8)));
+        let shifted = filter.shifted(&Shift::insert(Axis::Column, 0, 1)).unwrap();
+        assert_eq!(shifted.range, Range::new((2, 2), (4, 6)));
+        assert!(shifted.criteria.contains_key(&4));
+        let shifted = filter.shifted(&Shift::delete(Axis::Column, 3, 1)).unwrap();
+        assert_eq!(shifted.range, Range::new((1, 2), (2, 6)));
+        assert!(shifted.criteria.is_empty());
+        assert_eq!(filter.shifted(&Shift::delete(Axis::Row, 2, 1)), None);
+    }
+
+    #[test]
+    fn subtotals_skip_hidden_rows() {
+        let mut sheet = numbers();
+        sheet.enter((1, 0), "=SUBTOTAL(9,A2:A6)");
+        assert_eq!(sheet.shown((1, 0)), "55");
+        let mut filter = AutoFilter::new(Range::new((0, 0), (0, 5)));
+        filter.criteria.insert(
+            0,
+            Criterion::Top {
+                count: 1,
+                bottom: false,
+            },
+        );
+        sheet.set_filter(Some(filter));
+        assert!(sheet.is_hidden(1) && !sheet.is_hidden(2));
+        assert_eq!(sheet.shown((1, 0)), "40");
+        sheet.set_filter(None);
+        assert_eq!(sheet.shown((1, 0)), "55");
+    }
+}
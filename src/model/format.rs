@@ -0,0 +1,133 @@
+use std::fmt::Write;
+
+use rust_decimal::Decimal;
+
+use super::{number::Number, settings::Rounding, sheet::Cell};
+
+/// How the value of a cell is presented in the sheet
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum Format {
+    #[default]
+    General,
+    /// `$1,234.50`, values in these cells are kept as exact decimals
+    Currency,
+    /// `12.5%`
+    Percent,
+    /// `1.2e6`
+    Scientific,
+    /// `1,234,567`
+    Thousands,
+    /// `1,234` with negative values in parentheses, `(300)`
+    Accounting,
+}
+
+impl Format {
+    pub fn from_name(name: &str) -> Option<Format> {
+        match name {
+            "general" => Some(Format::General),
+            "currency" => Some(Format::Currency),
+            "percent" => Some(Format::Percent),
+            "scientific" => Some(Format::Scientific),
+            "thousands" => Some(Format::Thousands),
+            "accounting" => Some(Format::Accounting),
+            _ => None,
+        }
+    }
+
+    /// Converts `n` into the representation this format calculates with
+    pub fn coerce(&self, n: Number) -> Number {
+        match self {
+            Format::Currency => n.to_decimal().map_or(n, Number::Decimal),
+            _ => n,
+        }
+    }
+
+    /// Writes the value of `cell` as it should be shown in the sheet
+    pub fn write_cell(
+        &self,
+        cell: &Cell,
+        rounding: Rounding,
+        f: &mut impl Write,
+    ) -> std::fmt::Result {
+        let d = match (self, cell.val().map(|n| n.to_decimal())) {
+            (Format::General, _) | (_, None) | (_, Some(Err(_))) => return write!(f, "{}", cell),
+            (_, Some(Ok(d))) => d,
+        };
+        let negative = d.is_sign_negative() && !d.is_zero();
+
+        match self {
+            Format::Currency => {
+                let d = d.round_dp_with_strategy(2, rounding.strategy());
+                let s = format!("{:.2}", d.abs());
+                let (int, frac) = s.split_at(s.len() - 3);
+                let sign = if negative && !d.is_zero() { "-" } else { "" };
+                write!(f, "{}${}{}", sign, group_thousands(int), frac)
+            }
+            Format::Percent => match d.checked_mul(Decimal::ONE_HUNDRED) {
+                Some(p) => write!(f, "{}%", p.normalize()),
+                None => write!(f, "{}", cell),
+            },
+            Format::Scientific => {
+                let s = format!("{:e}", d);
+                let (mantissa, exp) = s.split_at(s.find('e').unwrap_or(s.len()));
+                let mantissa = match mantissa.contains('.') {
+                    true => mantissa.trim_end_matches('0').trim_end_matches('.'),
+                    false => mantissa,
+                };
+                write!(f, "{}{}", mantissa, exp)
+            }
+            Format::Thousands | Format::Accounting => {
+                let s = d.abs().to_string();
+                let (int, frac) = s.split_at(s.find('.').unwrap_or(s.len()));
+                let grouped = group_thousands(int);
+                match (self, negative) {
+                    (Format::Accounting, true) => write!(f, "({}{})", grouped, frac),
+                    (_, true) => write!(f, "-{}{}", grouped, frac),
+                    _ => write!(f, "{}{}", grouped, frac),
+                }
+            }
+            Format::General => unreachable!(),
+        }
+    }
+}
+
+/// Inserts a `,` between every group of three digits
+fn group_thousands(digits: &str) -> String {
+    let mut s = String::with_capacity(digits.len() + digits.len() / 3);
+    for (i, ch) in digits.chars().enumerate() {
+        if i > 0 && (digits.len() - i).is_multiple_of(3) {
+            s.push(',');
+        }
+        s.push(ch);
+    }
+    s
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::model::sheet::Sheet;
+
+    use super::*;
+
+    fn shown(format: Format, entry: &str) -> String {
+        let mut sheet = Sheet::new();
+        sheet.set_format(format, (0, 0));
+        sheet.enter((0, 0), entry);
+        sheet.shown((0, 0))
+    }
+
+    #[test]
+    fn currency() {
+        assert_eq!(shown(Format::Currency, "1234.5"), "$1,234.50");
+        assert_eq!(shown(Format::Currency, "-0.004"), "$0.00");
+        assert_eq!(shown(Format::Currency, "=-10/4"), "-$2.50");
+    }
+
+    #[test]
+    fn groups_of_thousands() {
+        assert_eq!(shown(Format::Thousands, "123"), "123");
+        assert_eq!(shown(Format::Thousands, "1234567"), "1,234,567");
+        assert_eq!(shown(Format::Thousands, "123456.75"), "123,456.75");
+        assert_eq!(shown(Format::Accounting, "-300"), "(300)");
+    }
+}
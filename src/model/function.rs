@@ -0,0 +1,617 @@
+use std::{
+    cell::RefCell,
+    collections::VecDeque,
+    rc::Rc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use regex::Regex;
+use rust_decimal::{
+    prelude::{FromPrimitive, ToPrimitive},
+    Decimal, RoundingStrategy,
+};
+
+use super::{
+    calc::{Context, ExecutionError, Operand},
+    number::Number,
+    reference::{self, Range},
+    settings::Settings,
+    sheet::{COLUMNS, ROWS},
+    value::Value,
+};
+
+/// Built in functions callable from formulas
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Function {
+    Round,
+    RoundUp,
+    RoundDown,
+    MRound,
+    Rand,
+    RandBetween,
+    Now,
+    Sum,
+    Subtotal,
+    Indirect,
+    Offset,
+    RegexMatch,
+    RegexExtract,
+    RegexReplace,
+    /// Control flow, these are compiled into jumps and never called
+    If,
+    IfError,
+    And,
+    Or,
+}
+
+/// Every function with the name it is called by
+const NAMES: [(&str, Function); 18] = [
+    ("ROUND", Function::Round),
+    ("ROUNDUP", Function::RoundUp),
+    ("ROUNDDOWN", Function::RoundDown),
+    ("MROUND", Function::MRound),
+    ("RAND", Function::Rand),
+    ("RANDBETWEEN", Function::RandBetween),
+    ("NOW", Function::Now),
+    ("SUM", Function::Sum),
+    ("SUBTOTAL", Function::Subtotal),
+    ("INDIRECT", Function::Indirect),
+    ("OFFSET", Function::Offset),
+    ("REGEXMATCH", Function::RegexMatch),
+    ("REGEXEXTRACT", Function::RegexExtract),
+    ("REGEXREPLACE", Function::RegexReplace),
+    ("IF", Function::If),
+    ("IFERROR", Function::IfError),
+    ("AND", Function::And),
+    ("OR", Function::Or),
+];
+
+/// Most compiled patterns kept per formula, patterns read from cells can
+/// differ for every cell sharing the formula
+const REGEX_CACHE_SIZE: usize = 32;
+
+/// Per formula state kept between evaluations
+#[derive(Debug, Clone, Default)]
+pub struct Cache {
+    /// Compiled patterns with their source text, the most recently used
+    /// first
+    regex: RefCell<VecDeque<(String, Rc<Regex>)>>,
+}
+
+impl Cache {
+    fn regex(&self, pattern: &str) -> Result<Rc<Regex>, ExecutionError> {
+        let mut cache = self.regex.borrow_mut();
+        if let Some(i) = cache.iter().position(|(p, _)| p == pattern) {
+            let entry = cache.remove(i).unwrap();
+            let r = entry.1.clone();
+            cache.push_front(entry);
+            return Ok(r);
+        }
+
+        let r = Rc::new(Regex::new(pattern).map_err(|_| ExecutionError::InvalidArgument)?);
+        cache.push_front((pattern.to_owned(), r.clone()));
+        cache.truncate(REGEX_CACHE_SIZE);
+        Ok(r)
+    }
+}
+
+impl Function {
+    pub fn from_name(name: &str) -> Option<Function> {
+        let name = name.to_ascii_uppercase();
+        NAMES.iter().find(|(n, _)| *n == name).map(|(_, f)| *f)
+    }
+
+    /// The name the function is called by in formulas
+    pub fn name(&self) -> &'static str {
+        NAMES.iter().find(|(_, f)| f == self).map_or("", |(n, _)| n)
+    }
+
+    /// The least and most arguments the function accepts
+    pub fn arity(&self) -> (usize, usize) {
+        match self {
+            Function::Round => (1, 2),
+            Function::RoundUp => (1, 2),
+            Function::RoundDown => (1, 2),
+            Function::MRound => (2, 2),
+            Function::Rand => (0, 0),
+            Function::RandBetween => (2, 2),
+            Function::Now => (0, 0),
+            Function::Sum => (1, 255),
+            Function::Subtotal => (2, 255),
+            Function::Indirect => (1, 1),
+            Function::Offset => (3, 5),
+            Function::RegexMatch => (2, 2),
+            Function::RegexExtract => (2, 2),
+            Function::RegexReplace => (3, 3),
+            Function::If => (2, 3),
+            Function::IfError => (2, 2),
+            Function::And => (1, 255),
+            Function::Or => (1, 255),
+        }
+    }
+
+    /// Volatile functions give a new result on every recalculation
+    pub fn is_volatile(&self) -> bool {
+        matches!(self, Function::Rand | Function::RandBetween | Function::Now)
+    }
+
+    pub fn call(
+        &self,
+        args: Vec<Operand>,
+        ctx: &mut Context,
+        cache: &Cache,
+    ) -> Result<Operand, ExecutionError> {
+        match self {
+            Function::RegexMatch | Function::RegexExtract | Function::RegexReplace => {
+                let args = args
+                    .into_iter()
+                    .map(|a| Ok(ctx.value(a)?.to_string()))
+                    .collect::<Result<Vec<_>, _>>()?;
+                self.call_regex(&args, cache).map(Operand::Value)
+            }
+            Function::Sum => sum(args, ctx).map(|n| Operand::Value(Value::Number(n))),
+            Function::And | Function::Or => {
+                logical(*self == Function::And, args, ctx).map(Operand::Value)
+            }
+            Function::Subtotal => subtotal(args, ctx).map(|n| Operand::Value(Value::Number(n))),
+            Function::Indirect => {
+                let text = ctx.value(args[0].clone())?;
+                reference::parse_range(text.text()?)
+                    .map(Operand::Ref)
+                    .ok_or(ExecutionError::InvalidReference)
+            }
+            Function::Offset => offset(args, ctx).map(Operand::Ref),
+            _ => {
+                let args = args
+                    .into_iter()
+                    .map(|a| ctx.number(a))
+                    .collect::<Result<Vec<_>, _>>()?;
+                self.call_numeric(args, ctx)
+                    .map(|n| Operand::Value(Value::Number(n)))
+            }
+        }
+    }
+
+    /// Calls a regular expression function, the pattern is always the second
+    /// argument
+    fn call_regex(&self, args: &[String], cache: &Cache) -> Result<Value, ExecutionError> {
+        let re = cache.regex(&args[1])?;
+
+        match self {
+            Function::RegexMatch => Ok(Value::Bool(re.is_match(&args[0]))),
+            Function::RegexExtract => {
+                let caps = re.captures(&args[0]).ok_or(ExecutionError::NoMatch)?;
+                let m = caps.get(1).or_else(|| caps.get(0)).unwrap();
+                Ok(Value::Text(m.as_str().to_owned()))
+            }
+            Function::RegexReplace => Ok(Value::Text(
+                re.replace_all(&args[0], args[2].as_str()).into_owned(),
+            )),
+            _ => Err(ExecutionError::NotImpemented),
+        }
+    }
+
+    /// Calls a function taking only numbers
+    fn call_numeric(&self, args: Vec<Number>, ctx: &mut Context) -> Result<Number, ExecutionError> {
+        let settings = &ctx.sheet.settings;
+        let digits = || match args.get(1) {
+            Some(d) => integer(&d.round(0, RoundingStrategy::ToZero)?),
+            None => Ok(0),
+        };
+
+        match self {
+            Function::Round => args[0].round(digits()?, settings.rounding.strategy()),
+            Function::RoundUp => args[0].round(digits()?, RoundingStrategy::AwayFromZero),
+            Function::RoundDown => args[0].round(digits()?, RoundingStrategy::ToZero),
+            Function::MRound => mround(&args[0], &args[1], settings),
+            Function::Rand => Ok(Number::Decimal(ctx.rng.next_decimal())),
+            Function::RandBetween => {
+                let low = integer(&args[0].round(0, RoundingStrategy::AwayFromZero)?)?;
+                let high = integer(&args[1].round(0, RoundingStrategy::ToZero)?)?;
+                if low > high {
+                    return Err(ExecutionError::InvalidArgument);
+                }
+                Ok(Number::Int(ctx.rng.between(low, high)))
+            }
+            Function::Now => now(),
+            _ => Err(ExecutionError::NotImpemented),
+        }
+    }
+}
+
+/// Rounds `n` to the nearest multiple of `multiple`
+fn mround(n: &Number, multiple: &Number, settings: &Settings) -> Result<Number, ExecutionError> {
+    if multiple.is_zero() {
+        return Ok(Number::Int(0));
+    }
+
+    let (d, m) = (n.to_decimal()?, multiple.to_decimal()?);
+    if d.is_sign_negative() != m.is_sign_negative() && !d.is_zero() {
+        return Err(ExecutionError::InvalidArgument);
+    }
+
+    let steps = d
+        .checked_div(m)
+        .ok_or(ExecutionError::Overflow)?
+        .round_dp_with_strategy(0, settings.rounding.strategy());
+    let res: Decimal = steps.checked_mul(m).ok_or(ExecutionError::Overflow)?;
+
+    match (n, multiple) {
+        (Number::Decimal(_), _) | (_, Number::Decimal(_)) => Ok(Number::Decimal(res)),
+        _ => Ok(Number::Int(
+            res.try_into().map_err(|_| ExecutionError::Overflow)?,
+        )),
+    }
+}
+
+fn integer(n: &Number) -> Result<i64, ExecutionError> {
+    n.to_decimal()?
+        .try_into()
+        .map_err(|_| ExecutionError::Overflow)
+}
+
+/// The current time as a serial date, days since 1899-12-30
+fn now() -> Result<Number, ExecutionError> {
+    let millis = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_err(|_| ExecutionError::InvalidArgument)?
+        .as_millis() as i64;
+    let days = Decimal::new(millis, 3) / Decimal::from(86_400) + Decimal::from(25_569);
+    Ok(Number::Decimal(days.round_dp(10)))
+}
+
+/// Adds up numbers and the numbers in ranges, text in ranges is skipped
+fn sum(args: Vec<Operand>, ctx: &mut Context) -> Result<Number, ExecutionError> {
+    let big = ctx.sheet.settings.big_int;
+    let mut total = Number::Int(0);
+
+    for a in args {
+        match a {
+            Operand::Ref(r) => {
+                for v in ctx.values(r) {
+                    if let Value::Number(n) = v? {
+                        total = total.add(&n, big)?;
+                    }
+                }
+            }
+            _ => total = total.add(&ctx.number(a)?, big)?,
+        }
+    }
+
+    Ok(total)
+}
+
+/// Whether every value is true, or with `and` false whether any of them is.
+/// Ranges count with their values apart from text, like SUM, and fail if
+/// there is no value at all.
+fn logical(and: bool, args: Vec<Operand>, ctx: &mut Context) -> Result<Value, ExecutionError> {
+    let mut any = false;
+    for a in args {
+        let values = match a {
+            Operand::Ref(r) if !r.is_cell() => ctx
+                .values(r)
+                .into_iter()
+                .filter(|v| !matches!(v, Ok(Value::Text(_))))
+                .collect(),
+            a => vec![ctx.value(a)],
+        };
+        for v in values {
+            if v?.truthy()? != and {
+                return Ok(Value::Bool(!and));
+            }
+            any = true;
+        }
+    }
+    match any {
+        true => Ok(Value::Bool(and)),
+        false => Err(ExecutionError::WrongType),
+    }
+}
+
+/// Aggregates numbers and the numbers in ranges like the function picked by
+/// the first argument, leaving out rows hidden by the autofilter and the
+/// results of other subtotals: 1 AVERAGE, 2 COUNT, 3 COUNTA, 4 MAX, 5 MIN,
+/// 6 PRODUCT, 7 STDEV, 8 STDEVP, 9 SUM, 10 VAR and 11 VARP. 101 to 111 pick
+/// the same, there are no rows hidden by hand to tell them apart.
+fn subtotal(args: Vec<Operand>, ctx: &mut Context) -> Result<Number, ExecutionError> {
+    let big = ctx.sheet.settings.big_int;
+    let mut args = args.into_iter();
+    let function = match integer(&ctx.number(args.next().ok_or(ExecutionError::InvalidArgument)?)?)?
+    {
+        f @ 1..=11 => f,
+        f @ 101..=111 => f - 100,
+        _ => return Err(ExecutionError::InvalidArgument),
+    };
+
+    let (mut numbers, mut values) = (vec![], 0);
+    for a in args {
+        match a {
+            Operand::Ref(r) => {
+                for v in ctx.visible_values(r) {
+                    values += 1;
+                    if let Value::Number(n) = v? {
+                        numbers.push(n);
+                    }
+                }
+            }
+            _ => {
+                values += 1;
+                numbers.push(ctx.number(a)?);
+            }
+        }
+    }
+
+    let total = || {
+        numbers
+            .iter()
+            .try_fold(Number::Int(0), |t, n| t.add(n, big))
+    };
+    let decimals = || {
+        numbers
+            .iter()
+            .map(Number::to_decimal)
+            .collect::<Result<Vec<_>, _>>()
+    };
+    let count = numbers.len();
+    match function {
+        1 => mean(&decimals()?).map(Number::Decimal),
+        2 => Ok(Number::Int(count as i64)),
+        3 => Ok(Number::Int(values)),
+        4 => Ok(numbers
+            .into_iter()
+            .max_by(|a, b| a.compare(b))
+            .unwrap_or(Number::Int(0))),
+        5 => Ok(numbers
+            .into_iter()
+            .min_by(|a, b| a.compare(b))
+            .unwrap_or(Number::Int(0))),
+        6 => numbers
+            .iter()
+            .try_fold(Number::Int(1), |p, n| p.mul(n, big)),
+        9 => total(),
+        _ => {
+            let sample = matches!(function, 7 | 10);
+            let variance = variance(&decimals()?, sample)?;
+            match function {
+                7 | 8 => variance
+                    .to_f64()
+                    .and_then(|v| Decimal::from_f64(v.sqrt()))
+                    .map(|d| Number::Decimal(d.normalize()))
+                    .ok_or(ExecutionError::Overflow),
+                _ => Ok(Number::Decimal(variance.normalize())),
+            }
+        }
+    }
+}
+
+fn mean(numbers: &[Decimal]) -> Result<Decimal, ExecutionError> {
+    if numbers.is_empty() {
+        return Err(ExecutionError::DivByZero);
+    }
+    let total = numbers
+        .iter()
+        .try_fold(Decimal::ZERO, |t, n| t.checked_add(*n))
+        .ok_or(ExecutionError::Overflow)?;
+    total
+        .checked_div(Decimal::from(numbers.len()))
+        .map(|d| d.normalize())
+        .ok_or(ExecutionError::Overflow)
+}
+
+/// The variance of a sample, or of the whole population without `sample`
+fn variance(numbers: &[Decimal], sample: bool) -> Result<Decimal, ExecutionError> {
+    let mean = mean(numbers)?;
+    let n = numbers.len() - sample as usize;
+    if n == 0 {
+        return Err(ExecutionError::DivByZero);
+    }
+    let squares = numbers
+        .iter()
+        .map(|x| (x - mean).checked_mul(x - mean))
+        .try_fold(Decimal::ZERO, |t, s| t.checked_add(s?))
+        .ok_or(ExecutionError::Overflow)?;
+    squares
+        .checked_div(Decimal::from(n))
+        .ok_or(ExecutionError::Overflow)
+}
+
+/// The range `rows` down and `cols` right of the reference, optionally
+/// resized to `height` by `width`
+fn offset(args: Vec<Operand>, ctx: &mut Context) -> Result<Range, ExecutionError> {
+    let base = match args[0] {
+        Operand::Ref(r) => r,
+        Operand::Value(_) => return Err(ExecutionError::WrongType),
+    };
+
+    let mut n = |i: usize, default: usize| -> Result<i64, ExecutionError> {
+        match args.get(i) {
+            Some(a) => integer(&ctx.number(a.clone())?),
+            None => Ok(default as i64),
+        }
+    };
+    let (rows, cols) = (n(1, 0)?, n(2, 0)?);
+    let (height, width) = (n(3, base.height())?, n(4, base.width())?);
+
+    // The column or row `by` after `v`, which has to be on the sheet
+    let shift = |v: usize, by: i64, end: usize| -> Result<usize, ExecutionError> {
+        (v as i64)
+            .checked_add(by)
+            .and_then(|i| usize::try_from(i).ok())
+            .filter(|i| *i < end)
+            .ok_or(ExecutionError::InvalidReference)
+    };
+    if height < 1 || width < 1 {
+        return Err(ExecutionError::InvalidReference);
+    }
+    let from = (
+        shift(base.from.0, cols, COLUMNS)?,
+        shift(base.from.1, rows, ROWS)?,
+    );
+    let to = (
+        shift(from.0, width - 1, COLUMNS)?,
+        shift(from.1, height - 1, ROWS)?,
+    );
+
+    Ok(Range::new(from, to))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::model::{settings::Rounding, sheet::Sheet};
+
+    use super::*;
+
+    /// Enters each formula in column A and gives what they show
+    fn shown(sheet: &mut Sheet, formulas: &[&str]) -> Vec<String> {
+        for (y, f) in formulas.iter().enumerate() {
+            sheet.enter((0, y), f);
+        }
+        (0..formulas.len()).map(|y| sheet.shown((0, y))).collect()
+    }
+
+    #[test]
+    fn rounding_functions() {
+        let mut sheet = Sheet::new();
+        let formulas = [
+            "=ROUND(2.345,2)",
+            "=ROUND(10/4,1)",
+            "=ROUND(1250,-2)",
+            "=ROUNDUP(1.21,1)",
+            "=ROUNDDOWN(-1.29,1)",
+            "=MROUND(17,5)",
+        ];
+        assert_eq!(
+            shown(&mut sheet, &formulas),
+            ["2.35", "2.5", "1300", "1.3", "-1.2", "15"]
+        );
+    }
+
+    #[test]
+    fn rounding_mode_recomputes_formulas() {
+        let mut sheet = Sheet::new();
+        assert_eq!(
+            shown(&mut sheet, &["=ROUND(2.5,0)", "=MROUND(25,10)"]),
+            ["3", "30"]
+        );
+        sheet.set_rounding(Rounding::HalfEven);
+        assert_eq!(sheet.shown((0, 0)), "2");
+        assert_eq!(sheet.shown((0, 1)), "20");
+    }
+
+    #[test]
+    fn volatile_functions_follow_the_seed() {
+        let mut sheet = Sheet::new();
+        shown(&mut sheet, &["=RAND()", "=RANDBETWEEN(1,6)", "=RAND()"]);
+        let values = |sheet: &Sheet| (0..3).map(|y| sheet.shown((0, y))).collect::<Vec<_>>();
+
+        sheet.set_seed(1);
+        let first = values(&sheet);
+        // Every cell draws its own numbers
+        assert_ne!(first[0], first[2]);
+        assert!(["1", "2", "3", "4", "5", "6"].contains(&first[1].as_str()));
+
+        // Every recalculation draws new ones, the seed replays them
+        sheet.recalculate();
+        assert_ne!(values(&sheet), first);
+        sheet.set_seed(1);
+        assert_eq!(values(&sheet), first);
+    }
+
+    #[test]
+    fn randbetween_needs_ordered_bounds() {
+        let mut sheet = Sheet::new();
+        assert_eq!(
+            shown(&mut sheet, &["=RANDBETWEEN(3,3)", "=RANDBETWEEN(4,3)"]),
+            ["3", "#Error"]
+        );
+    }
+
+    #[test]
+    fn indirect_and_offset() {
+        let mut sheet = Sheet::new();
+        sheet.enter((1, 0), "1");
+        sheet.enter((1, 1), "2");
+        sheet.enter((1, 2), "3");
+        sheet.enter((2, 0), "B2");
+        let formulas = [
+            "=INDIRECT(C1)",
+            "=SUM(INDIRECT(\"B1:B3\"))",
+            "=INDIRECT(\"$B$3\")",
+            "=SUM(OFFSET(B1,1,0,2))",
+            "=OFFSET(B1,2,0)",
+            "=INDIRECT(\"nowhere\")",
+        ];
+        assert_eq!(
+            shown(&mut sheet, &formulas),
+            ["2", "6", "3", "5", "3", "#Error"]
+        );
+    }
+
+    #[test]
+    fn offset_stays_on_the_sheet() {
+        let mut sheet = Sheet::new();
+        let formulas = [
+            "=OFFSET(A2,9223372036854775806,0)",
+            "=OFFSET(A2,-2,0)",
+            "=OFFSET(B1,0,16383)",
+            "=SUM(OFFSET(B1,0,16382,1,2))",
+            "=SUM(OFFSET(B1,1048575,0,2,1))",
+            "=SUM(OFFSET(B1,1048575,0,1,1))",
+        ];
+        let shown = shown(&mut sheet, &formulas);
+        assert_eq!(shown[..5], ["#Error"; 5]);
+        assert_eq!(shown[5], "0");
+    }
+
+    #[test]
+    fn runtime_ranges_follow_empty_cells() {
+        let mut sheet = Sheet::new();
+        sheet.enter((1, 0), "1");
+        shown(
+            &mut sheet,
+            &["=SUM(INDIRECT(\"B1:B3\"))", "=SUM(OFFSET(B1,0,0,3))"],
+        );
+        sheet.enter((1, 1), "5");
+        sheet.enter((1, 2), "7");
+        assert_eq!(sheet.shown((0, 0)), "13");
+        assert_eq!(sheet.shown((0, 1)), "13");
+    }
+
+    #[test]
+    fn regex_functions() {
+        let mut sheet = Sheet::new();
+        sheet.enter((1, 0), "Order 1234 shipped");
+        let formulas = [
+            "=REGEXMATCH(B1,\"\\d+\")",
+            "=REGEXEXTRACT(B1,\"(\\d+) (\\w+)\")",
+            "=REGEXEXTRACT(B1,\"[a-z]+\")",
+            "=REGEXREPLACE(B1,\"\\d\",\"#\")",
+            "=REGEXEXTRACT(B1,\"x+\")",
+            "=REGEXMATCH(B1,\"(\")",
+        ];
+        let expected = [
+            "TRUE",
+            "1234",
+            "rder",
+            "Order #### shipped",
+            "#Error",
+            "#Error",
+        ];
+        assert_eq!(shown(&mut sheet, &formulas), expected);
+    }
+
+    #[test]
+    fn regex_cache_keeps_the_most_recent_patterns() {
+        let cache = Cache::default();
+        let first = cache.regex("a+").unwrap();
+        for i in 0..REGEX_CACHE_SIZE - 1 {
+            cache.regex(&format!("b{}", i)).unwrap();
+        }
+        // Using the first pattern again keeps it when the next one is added
+        assert!(Rc::ptr_eq(&first, &cache.regex("a+").unwrap()));
+        cache.regex("c").unwrap();
+        assert_eq!(cache.regex.borrow().len(), REGEX_CACHE_SIZE);
+        assert!(Rc::ptr_eq(&first, &cache.regex("a+").unwrap()));
+        assert!(cache.regex.borrow().iter().all(|(p, _)| p != "b0"));
+    }
+}
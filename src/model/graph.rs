@@ -0,0 +1,182 @@
+//! Which cells read from which
+
+use std::collections::{HashMap, HashSet};
+
+use super::reference::Range;
+
+type Pos = (usize, usize);
+
+/// Columns in each band the ranges are indexed by
+const BAND: usize = 64;
+
+/// The references between cells, kept in both directions so they can be
+/// followed from a formula to what it reads and from a cell to the formulas
+/// reading it
+#[derive(Debug, Default)]
+pub struct Graph {
+    /// For every formula, the cells and ranges it reads from
+    precedents: HashMap<Pos, Vec<Range>>,
+    /// For every cell, the formulas reading it on its own
+    dependents: HashMap<Pos, Vec<Pos>>,
+    /// Ranges of more than one cell with the formula reading them, under
+    /// every band of columns they overlap. Only the ranges of the band of a
+    /// cell are searched for it, and a long column costs no more than a
+    /// single reference.
+    ranges: HashMap<usize, Vec<(Range, Pos)>>,
+}
+
+impl Graph {
+    /// Replaces everything the formula at `pos` reads from
+    pub fn set_precedents(&mut self, pos: Pos, precedents: Vec<Range>) {
+        self.remove(pos);
+
+        let mut unique: Vec<Range> = vec![];
+        for r in precedents {
+            if !unique.contains(&r) {
+                unique.push(r);
+            }
+        }
+        if unique.is_empty() {
+            return;
+        }
+        for r in &unique {
+            if r.is_cell() {
+                self.dependents.entry(r.from).or_default().push(pos);
+            } else {
+                for band in bands(r) {
+                    self.ranges.entry(band).or_default().push((*r, pos));
+                }
+            }
+        }
+        self.precedents.insert(pos, unique);
+    }
+
+    /// Removes the edges from `pos` to the cells it reads from, as when the
+    /// formula in it is overwritten. Formulas reading from `pos` keep theirs.
+    pub fn remove(&mut self, pos: Pos) {
+        let precedents = self.precedents.remove(&pos).unwrap_or_default();
+        for r in &precedents {
+            if !r.is_cell() {
+                for band in bands(r) {
+                    if let Some(ranges) = self.ranges.get_mut(&band) {
+                        ranges.retain(|(_, d)| *d != pos);
+                        if ranges.is_empty() {
+                            self.ranges.remove(&band);
+                        }
+                    }
+                }
+            } else if let Some(dependents) = self.dependents.get_mut(&r.from) {
+                dependents.retain(|d| *d != pos);
+                if dependents.is_empty() {
+                    self.dependents.remove(&r.from);
+                }
+            }
+        }
+    }
+
+    pub fn precedents(&self, pos: Pos) -> &[Range] {
+        self.precedents.get(&pos).map_or(&[], |p| p.as_slice())
+    }
+
+    /// The formulas reading `pos`, on its own or as part of a range
+    pub fn dependents(&self, pos: Pos) -> Vec<Pos> {
+        let mut dependents = self.dependents.get(&pos).cloned().unwrap_or_default();
+        let ranges = self
+            .ranges
+            .get(&(pos.0 / BAND))
+            .map_or(&[][..], |r| r.as_slice());
+        let mut seen: HashSet<Pos> = dependents.iter().copied().collect();
+        for (r, d) in ranges {
+            if r.contains(pos) && seen.insert(*d) {
+                dependents.push(*d);
+            }
+        }
+        dependents
+    }
+
+    /// The shortest cycle through `pos`, starting and ending with it and
+    /// following each cell to a formula reading from it
+    pub fn cycle(&self, pos: Pos) -> Option<Vec<Pos>> {
+        // Breadth first, remembering where each cell was reached from
+        let mut from: HashMap<Pos, Pos> = HashMap::new();
+        let mut seen = HashSet::from([pos]);
+        let mut queue = vec![pos];
+        let mut i = 0;
+        while let Some(&p) = queue.get(i) {
+            for d in self.dependents(p) {
+                if d == pos {
+                    let mut path = vec![p];
+                    while let Some(&q) = from.get(path.last().unwrap()) {
+                        path.push(q);
+                    }
+                    path.reverse();
+                    path.push(pos);
+                    return Some(path);
+                }
+                if seen.insert(d) {
+                    from.insert(d, p);
+                    queue.push(d);
+                }
+            }
+            i += 1;
+        }
+        None
+    }
+}
+
+/// The bands of columns `range` overlaps
+fn bands(range: &Range) -> std::ops::RangeInclusive<usize> {
+    range.from.0 / BAND..=range.to.0 / BAND
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dependents_through_cells_and_ranges() {
+        let mut graph = Graph::default();
+        let wide = Range::new((10, 0), (200, 5));
+        graph.set_precedents((0, 0), vec![Range::new((100, 3), (100, 3)), wide]);
+        graph.set_precedents((1, 0), vec![Range::new((0, 0), (0, 1_000_000))]);
+
+        // Every band the range overlaps finds it, each formula once
+        assert_eq!(graph.dependents((100, 3)), [(0, 0)]);
+        assert_eq!(graph.dependents((10, 0)), [(0, 0)]);
+        assert_eq!(graph.dependents((200, 5)), [(0, 0)]);
+        assert_eq!(graph.dependents((201, 5)), []);
+        assert_eq!(graph.dependents((0, 999_999)), [(1, 0)]);
+        assert_eq!(graph.precedents((0, 0)).len(), 2);
+
+        graph.remove((0, 0));
+        assert_eq!(graph.dependents((100, 3)), []);
+        assert!(graph
+            .ranges
+            .values()
+            .all(|r| r.iter().all(|(_, d)| *d != (0, 0))));
+        assert_eq!(graph.precedents((0, 0)), []);
+    }
+
+    #[test]
+    fn shortest_cycle_path() {
+        let cell = |x, y| Range::new((x, y), (x, y));
+        let mut graph = Graph::default();
+        // A1 -> B1 -> C1 -> A1, and the longer way round A1 -> D1 -> E1 -> C1
+        graph.set_precedents((1, 0), vec![cell(0, 0)]);
+        graph.set_precedents((2, 0), vec![cell(1, 0), cell(4, 0)]);
+        graph.set_precedents((0, 0), vec![cell(2, 0)]);
+        graph.set_precedents((3, 0), vec![cell(0, 0)]);
+        graph.set_precedents((4, 0), vec![cell(3, 0)]);
+        assert_eq!(
+            graph.cycle((0, 0)),
+            Some(vec![(0, 0), (1, 0), (2, 0), (0, 0)])
+        );
+        assert_eq!(
+            graph.cycle((3, 0)),
+            Some(vec![(3, 0), (4, 0), (2, 0), (0, 0), (3, 0)])
+        );
+
+        graph.set_precedents((0, 0), vec![Range::new((5, 0), (9, 9))]);
+        assert_eq!(graph.cycle((0, 0)), None);
+    }
+}
@@ -1,2 +1,18 @@
-pub mod sheet;
 pub mod calc;
+pub mod fill;
+pub mod filter;
+pub mod format;
+pub mod function;
+pub mod graph;
+pub mod number;
+pub mod optimize;
+pub mod printer;
+pub mod random;
+pub mod reference;
+pub mod settings;
+pub mod sheet;
+pub mod shift;
+pub mod solver;
+pub mod sort;
+pub mod value;
+pub mod whatif;
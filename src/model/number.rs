@@ -0,0 +1,286 @@
+use std::{cmp::Ordering, fmt::Display, str::FromStr};
+
+use num_bigint::BigInt;
+use num_traits::{ToPrimitive, Zero};
+use rust_decimal::{Decimal, RoundingStrategy};
+
+use super::calc::ExecutionError;
+
+/// A numeric value as seen by the evaluator
+#[derive(Debug, Clone, PartialEq)]
+pub enum Number {
+    Int(i64),
+    /// Values that no longer fit in an `i64`, only produced in big integer mode
+    Big(BigInt),
+    /// Exact base 10 fractions, used for anything with a decimal point
+    Decimal(Decimal),
+}
+
+impl Number {
+    /// Turns `n` back into an `Int` if it fits
+    fn normalize(n: BigInt) -> Number {
+        match n.to_i64() {
+            Some(v) => Number::Int(v),
+            None => Number::Big(n),
+        }
+    }
+
+    fn to_big(&self) -> BigInt {
+        match self {
+            Number::Int(v) => BigInt::from(*v),
+            Number::Big(v) => v.clone(),
+            Number::Decimal(v) => BigInt::from_str(&v.trunc().to_string()).unwrap(),
+        }
+    }
+
+    pub fn to_decimal(&self) -> Result<Decimal, ExecutionError> {
+        match self {
+            Number::Int(v) => Ok(Decimal::from(*v)),
+            Number::Big(v) => {
+                Decimal::from_str(&v.to_string()).map_err(|_| ExecutionError::Overflow)
+            }
+            Number::Decimal(v) => Ok(*v),
+        }
+    }
+
+    pub fn compare(&self, other: &Number) -> Ordering {
+        match (self, other) {
+            (Number::Int(a), Number::Int(b)) => a.cmp(b),
+            (Number::Decimal(_), _) | (_, Number::Decimal(_)) => {
+                match (self.to_decimal(), other.to_decimal()) {
+                    (Ok(a), Ok(b)) => a.cmp(&b),
+                    _ => self.to_big().cmp(&other.to_big()),
+                }
+            }
+            _ => self.to_big().cmp(&other.to_big()),
+        }
+    }
+
+    pub fn is_zero(&self) -> bool {
+        match self {
+            Number::Int(v) => *v == 0,
+            Number::Big(v) => v.is_zero(),
+            Number::Decimal(v) => v.is_zero(),
+        }
+    }
+
+    /// Applies `small` when both sides are `Int` and the result does not
+    /// overflow, otherwise falls back to `large` if `big` is set. As soon as
+    /// one side is a decimal both are computed with `decimal`.
+    fn apply(
+        &self,
+        other: &Number,
+        big: bool,
+        small: fn(i64, i64) -> Option<i64>,
+        large: fn(BigInt, BigInt) -> BigInt,
+        decimal: fn(Decimal, Decimal) -> Option<Decimal>,
+    ) -> Result<Number, ExecutionError> {
+        match (self, other) {
+            (Number::Decimal(_), _) | (_, Number::Decimal(_)) => {
+                decimal(self.to_decimal()?, other.to_decimal()?)
+                    .map(Number::Decimal)
+                    .ok_or(ExecutionError::Overflow)
+            }
+            (Number::Int(a), Number::Int(b)) if small(*a, *b).is_some() => {
+                Ok(Number::Int(small(*a, *b).unwrap()))
+            }
+            _ if big => Ok(Number::normalize(large(self.to_big(), other.to_big()))),
+            _ => Err(ExecutionError::Overflow),
+        }
+    }
+
+    pub fn add(&self, other: &Number, big: bool) -> Result<Number, ExecutionError> {
+        self.apply(
+            other,
+            big,
+            i64::checked_add,
+            |a, b| a + b,
+            Decimal::checked_add,
+        )
+    }
+
+    pub fn sub(&self, other: &Number, big: bool) -> Result<Number, ExecutionError> {
+        self.apply(
+            other,
+            big,
+            i64::checked_sub,
+            |a, b| a - b,
+            Decimal::checked_sub,
+        )
+    }
+
+    pub fn mul(&self, other: &Number, big: bool) -> Result<Number, ExecutionError> {
+        self.apply(
+            other,
+            big,
+            i64::checked_mul,
+            |a, b| a * b,
+            Decimal::checked_mul,
+        )
+    }
+
+    /// Divides exactly, integers only stay integers when they divide evenly
+    /// and give a decimal otherwise
+    pub fn div(&self, other: &Number, big: bool) -> Result<Number, ExecutionError> {
+        if other.is_zero() {
+            return Err(ExecutionError::DivByZero);
+        }
+        let even = match (self, other) {
+            (Number::Decimal(_), _) | (_, Number::Decimal(_)) => true,
+            // The remainder only overflows for the smallest integer by -1
+            (Number::Int(a), Number::Int(b)) => a.checked_rem(*b).is_none_or(|r| r == 0),
+            _ => (self.to_big() % other.to_big()).is_zero(),
+        };
+        match even {
+            true => self.apply(
+                other,
+                big,
+                i64::checked_div,
+                |a, b| a / b,
+                Decimal::checked_div,
+            ),
+            false => self
+                .to_decimal()?
+                .checked_div(other.to_decimal()?)
+                .map(Number::Decimal)
+                .ok_or(ExecutionError::Overflow),
+        }
+    }
+
+    pub fn neg(&self, big: bool) -> Result<Number, ExecutionError> {
+        Number::Int(0).sub(self, big)
+    }
+
+    /// Divides by a hundred, always giving a decimal
+    pub fn percent(&self) -> Result<Number, ExecutionError> {
+        self.to_decimal()?
+            .checked_div(Decimal::ONE_HUNDRED)
+            .map(Number::Decimal)
+            .ok_or(ExecutionError::Overflow)
+    }
+
+    /// Rounds to `digits` decimal places, negative `digits` round to tens,
+    /// hundreds and so on. Integers stay integers.
+    pub fn round(&self, digits: i64, strategy: RoundingStrategy) -> Result<Number, ExecutionError> {
+        if digits >= 0 {
+            return match self {
+                Number::Decimal(d) => Ok(Number::Decimal(
+                    d.round_dp_with_strategy(digits.min(28) as u32, strategy),
+                )),
+                _ => Ok(self.clone()),
+            };
+        }
+
+        let mut scale = Decimal::ONE;
+        for _ in 0..digits.unsigned_abs() {
+            scale = scale
+                .checked_mul(Decimal::TEN)
+                .ok_or(ExecutionError::Overflow)?;
+        }
+        let rounded = self
+            .to_decimal()?
+            .checked_div(scale)
+            .ok_or(ExecutionError::Overflow)?
+            .round_dp_with_strategy(0, strategy)
+            .checked_mul(scale)
+            .ok_or(ExecutionError::Overflow)?;
+
+        match self {
+            Number::Decimal(_) => Ok(Number::Decimal(rounded)),
+            _ => Ok(Number::normalize(Number::Decimal(rounded).to_big())),
+        }
+    }
+}
+
+/// Parses plain numbers as well as `1_000_000` and `1.2e6`
+impl FromStr for Number {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.starts_with('_')
+            || s.ends_with('_')
+            || s.contains("__")
+            || s.contains("._")
+            || s.contains("_.")
+        {
+            return Err(());
+        }
+        let s = s.replace('_', "");
+
+        if s.contains(['e', 'E']) {
+            Decimal::from_scientific(&s)
+                .map(Number::Decimal)
+                .map_err(|_| ())
+        } else if s.contains('.') {
+            Decimal::from_str(&s).map(Number::Decimal).map_err(|_| ())
+        } else if let Ok(v) = s.parse() {
+            Ok(Number::Int(v))
+        } else {
+            BigInt::from_str(&s).map(Number::Big).map_err(|_| ())
+        }
+    }
+}
+
+impl Display for Number {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Number::Int(v) => write!(f, "{}", v),
+            Number::Big(v) => write!(f, "{}", v),
+            Number::Decimal(v) => write!(f, "{}", v),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn overflow_fails_unless_big() {
+        let max = Number::Int(i64::MAX);
+        assert_eq!(
+            max.add(&Number::Int(1), false),
+            Err(ExecutionError::Overflow)
+        );
+        let sum = max.add(&Number::Int(1), true).unwrap();
+        assert_eq!(sum, Number::Big(BigInt::from(i64::MAX) + 1));
+        // Results fitting again are plain integers
+        assert_eq!(sum.sub(&Number::Int(1), true), Ok(max));
+    }
+
+    #[test]
+    fn big_literals() {
+        let n: Number = "123456789012345678901234567890".parse().unwrap();
+        assert!(matches!(n, Number::Big(_)));
+        assert_eq!(n.to_string(), "123456789012345678901234567890");
+        assert_eq!("1_000".parse(), Ok(Number::Int(1000)));
+    }
+
+    #[test]
+    fn big_int_mode_recomputes_formulas() {
+        let mut sheet = crate::model::sheet::Sheet::new();
+        sheet.enter((0, 0), "=9223372036854775807*10");
+        assert_eq!(sheet.shown((0, 0)), "#Error");
+        sheet.set_big_int(true);
+        assert_eq!(sheet.shown((0, 0)), "92233720368547758070");
+        sheet.set_big_int(false);
+        assert_eq!(sheet.shown((0, 0)), "#Error");
+    }
+
+    #[test]
+    fn decimals_are_exact() {
+        let (a, b): (Number, Number) = ("0.1".parse().unwrap(), "0.2".parse().unwrap());
+        assert_eq!(a.add(&b, false), Ok(Number::Decimal(Decimal::new(3, 1))));
+        assert_eq!(a.add(&b, false).unwrap().to_string(), "0.3");
+    }
+
+    #[test]
+    fn division_gives_decimals_unless_even() {
+        let div = |a: i64, b: i64| Number::Int(a).div(&Number::Int(b), false);
+        assert_eq!(div(10, 5), Ok(Number::Int(2)));
+        assert_eq!(div(10, 4), Ok(Number::Decimal(Decimal::new(25, 1))));
+        assert!(div(1, 3).unwrap().to_string().starts_with("0.3333"));
+        assert_eq!(div(1, 0), Err(ExecutionError::DivByZero));
+        assert_eq!(div(i64::MIN, -1), Err(ExecutionError::Overflow));
+    }
+}
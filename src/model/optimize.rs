@@ -0,0 +1,171 @@
+//! Rewrites parsed formulas into equivalent ones that are cheaper to run
+
+use super::{
+    calc::{Expr, Node},
+    number::Number,
+    value::Value,
+};
+
+/// Chains of `+` with at least this many operands become one [Expr::Sum]
+const MIN_SUM_TERMS: usize = 3;
+
+/// Folds constant sub-expressions, drops operations that do not change their
+/// operand and turns long chains of additions into a single sum
+pub fn optimize(e: Expr) -> Expr {
+    match e {
+        Expr::Op(op, lhs, rhs) => {
+            let lhs = optimize(*lhs);
+            let rhs = optimize(*rhs);
+            if let (Expr::Val(a), Expr::Val(b)) = (&lhs, &rhs) {
+                if let Some(v) = fold(&op, a, b) {
+                    return Expr::Val(v);
+                }
+            }
+            match identity(&op, lhs, rhs) {
+                Identity::Removed(e) => e,
+                Identity::Kept(lhs, rhs) if op == Node::Add => sum(lhs, rhs),
+                Identity::Kept(lhs, rhs) => Expr::Op(op, Box::new(lhs), Box::new(rhs)),
+            }
+        }
+        Expr::Neg(e) => unary(Node::Neg, *e, Expr::Neg),
+        Expr::Percent(e) => unary(Node::Percent, *e, Expr::Percent),
+        Expr::Call(f, args) => Expr::Call(f, args.into_iter().map(optimize).collect()),
+        Expr::Sum(terms) => Expr::Sum(terms.into_iter().map(optimize).collect()),
+        e => e,
+    }
+}
+
+/// Calculates an operator on constants ahead of time. Errors are left for the
+/// evaluation so they can still be caught, and anything that could overflow
+/// without unbounded integers is left as well since that setting can change.
+fn fold(op: &Node, lhs: &Value, rhs: &Value) -> Option<Value> {
+    op.apply(lhs, rhs, false).ok()
+}
+
+fn unary(op: Node, e: Expr, wrap: fn(Box<Expr>) -> Expr) -> Expr {
+    match optimize(e) {
+        Expr::Val(v) => match fold(&op, &v, &v) {
+            Some(folded) => Expr::Val(folded),
+            None => wrap(Box::new(Expr::Val(v))),
+        },
+        e => wrap(Box::new(e)),
+    }
+}
+
+/// What is left of an operation by a neutral operand
+enum Identity {
+    /// The other operand, on its own
+    Removed(Expr),
+    /// Both operands, the operation still has to be done
+    Kept(Expr, Expr),
+}
+
+/// Removes `x + 0`, `x - 0`, `x * 1` and `x / 1`, giving back the operands if
+/// there is nothing to remove. Only done when `x` is known to be a number, as
+/// the operation would otherwise turn `TRUE` into 1 or fail on text.
+fn identity(op: &Node, lhs: Expr, rhs: Expr) -> Identity {
+    let is = |e: &Expr, n: i64| matches!(e, Expr::Val(Value::Number(Number::Int(i))) if *i == n);
+    let commutes = matches!(op, Node::Add | Node::Mul);
+    let neutral = match op {
+        Node::Add | Node::Sub => 0,
+        Node::Mul | Node::Div => 1,
+        _ => return Identity::Kept(lhs, rhs),
+    };
+
+    if is(&rhs, neutral) && is_number(&lhs) {
+        Identity::Removed(lhs)
+    } else if commutes && is(&lhs, neutral) && is_number(&rhs) {
+        Identity::Removed(rhs)
+    } else {
+        Identity::Kept(lhs, rhs)
+    }
+}
+
+/// Whether `e` always evaluates to a number when it succeeds
+fn is_number(e: &Expr) -> bool {
+    match e {
+        Expr::Val(v) => matches!(v, Value::Number(_)),
+        Expr::Op(op, _, _) => matches!(op, Node::Add | Node::Sub | Node::Mul | Node::Div),
+        Expr::Neg(_) | Expr::Percent(_) | Expr::Sum(_) => true,
+        _ => false,
+    }
+}
+
+/// Joins `lhs + rhs` onto a chain of additions on the left, keeping the order
+/// of evaluation. Short chains stay binary operations.
+fn sum(lhs: Expr, rhs: Expr) -> Expr {
+    let mut terms = match lhs {
+        Expr::Sum(terms) => terms,
+        Expr::Op(Node::Add, a, b) => vec![*a, *b],
+        lhs => vec![lhs],
+    };
+    terms.push(rhs);
+
+    if terms.len() >= MIN_SUM_TERMS {
+        Expr::Sum(terms)
+    } else {
+        let rhs = terms.pop().unwrap();
+        let lhs = terms.pop().unwrap();
+        Expr::Op(Node::Add, Box::new(lhs), Box::new(rhs))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::model::reference::CellRef;
+
+    use super::*;
+
+    fn int(i: i64) -> Expr {
+        Expr::Val(Value::Number(Number::Int(i)))
+    }
+
+    fn op(op: Node, lhs: Expr, rhs: Expr) -> Expr {
+        Expr::Op(op, Box::new(lhs), Box::new(rhs))
+    }
+
+    fn cell() -> Expr {
+        Expr::Cell(CellRef::new((0, 0), (false, false), (1, 1)))
+    }
+
+    #[test]
+    fn folds_constants() {
+        let e = op(Node::Add, int(1), op(Node::Mul, int(2), int(3)));
+        assert_eq!(optimize(e), int(7));
+        assert_eq!(optimize(Expr::Neg(Box::new(int(4)))), int(-4));
+        assert_eq!(
+            optimize(op(Node::Lt, int(1), int(2))),
+            Expr::Val(Value::Bool(true))
+        );
+    }
+
+    #[test]
+    fn leaves_errors_and_overflows() {
+        let e = op(Node::Div, int(1), int(0));
+        assert_eq!(optimize(e.clone()), e);
+        let e = op(Node::Add, int(i64::MAX), int(1));
+        assert_eq!(optimize(e.clone()), e);
+    }
+
+    #[test]
+    fn removes_identities_of_numbers_only() {
+        let product = op(Node::Mul, cell(), int(2));
+        assert_eq!(optimize(op(Node::Add, product.clone(), int(0))), product);
+        assert_eq!(optimize(op(Node::Mul, int(1), product.clone())), product);
+        assert_eq!(optimize(op(Node::Div, product.clone(), int(1))), product);
+        // A cell may hold text or a boolean, the operation is not neutral
+        let e = op(Node::Add, cell(), int(0));
+        assert_eq!(optimize(e.clone()), e);
+        // Only the right side of a subtraction is neutral
+        let e = op(Node::Sub, int(0), product);
+        assert_eq!(optimize(e.clone()), e);
+    }
+
+    #[test]
+    fn chains_additions() {
+        let e = op(Node::Add, op(Node::Add, cell(), cell()), cell());
+        assert_eq!(optimize(e), Expr::Sum(vec![cell(), cell(), cell()]));
+        let e = op(Node::Add, cell(), cell());
+        assert_eq!(optimize(e.clone()), e);
+    }
+}
@@ -0,0 +1,177 @@
+//! Turns parsed formulas back into text
+
+use super::{
+    calc::{Expr, Node},
+    reference::{CellRef, INVALID_REFERENCE},
+    value::Value,
+};
+
+/// Prints `e` as written in the cell at `pos`, with upper case names, no
+/// spaces and only the parentheses needed to parse it back the same way
+pub fn print(e: &Expr, pos: (usize, usize)) -> String {
+    let mut s = String::new();
+    write(e, pos, &mut s);
+    s
+}
+
+fn write(e: &Expr, pos: (usize, usize), s: &mut String) {
+    match e {
+        Expr::Val(Value::Text(t)) => {
+            s.push('"');
+            s.push_str(&t.replace('"', "\"\""));
+            s.push('"');
+        }
+        Expr::Val(v) => s.push_str(&v.to_string()),
+        Expr::Cell(r) => s.push_str(&reference(r, pos)),
+        Expr::Range(a, b) if a.resolve(pos).is_none() || b.resolve(pos).is_none() => {
+            s.push_str(INVALID_REFERENCE)
+        }
+        Expr::Range(a, b) => {
+            s.push_str(&reference(a, pos));
+            s.push(':');
+            s.push_str(&reference(b, pos));
+        }
+        Expr::Op(op, lhs, rhs) => {
+            let p = op.precedence();
+            operand(lhs, level(lhs) > p, pos, s);
+            s.push_str(symbol(op));
+            operand(rhs, level(rhs) >= p, pos, s);
+        }
+        Expr::Neg(e) => {
+            s.push('-');
+            operand(e, level(e) > 1, pos, s);
+        }
+        Expr::Percent(e) => {
+            operand(e, level(e) > 0, pos, s);
+            s.push('%');
+        }
+        Expr::Call(f, args) => {
+            s.push_str(f.name());
+            s.push('(');
+            for (i, a) in args.iter().enumerate() {
+                if i > 0 {
+                    s.push(',');
+                }
+                write(a, pos, s);
+            }
+            s.push(')');
+        }
+        Expr::Sum(terms) => {
+            let p = Node::Add.precedence();
+            for (i, t) in terms.iter().enumerate() {
+                if i > 0 {
+                    s.push('+');
+                }
+                operand(t, level(t) > p || (i > 0 && level(t) == p), pos, s);
+            }
+        }
+    }
+}
+
+fn operand(e: &Expr, parens: bool, pos: (usize, usize), s: &mut String) {
+    if parens {
+        s.push('(');
+        write(e, pos, s);
+        s.push(')');
+    } else {
+        write(e, pos, s);
+    }
+}
+
+/// How loosely `e` binds, in the terms of [Node::precedence]. Operands of `%`
+/// have to be 0 and those of unary minus at most 1.
+fn level(e: &Expr) -> u8 {
+    match e {
+        Expr::Op(op, _, _) => op.precedence(),
+        Expr::Sum(_) => Node::Add.precedence(),
+        Expr::Neg(_) => 1,
+        _ => 0,
+    }
+}
+
+fn reference(r: &CellRef, pos: (usize, usize)) -> String {
+    r.name(pos).unwrap_or_else(|| INVALID_REFERENCE.to_owned())
+}
+
+/// Describes an instruction of the formula at `pos` for the step debugger
+pub fn describe(node: &Node, pos: (usize, usize)) -> String {
+    match node {
+        Node::Cell(r) => format!("ref {}", reference(r, pos)),
+        Node::Range(a, b) => format!("range {}:{}", reference(a, pos), reference(b, pos)),
+        Node::Val(v) => {
+            let mut s = String::from("push ");
+            write(&Expr::Val(v.clone()), pos, &mut s);
+            s
+        }
+        Node::Neg => "negate".to_owned(),
+        Node::Percent => "%".to_owned(),
+        Node::Call(f, argc) => format!("call {} with {}", f.name(), argc),
+        Node::Sum(n) => format!("add {}", n),
+        Node::Jump(t) => format!("jump to {}", t),
+        Node::JumpIf(t) => format!("jump to {} if true", t),
+        Node::JumpIfNot(t) => format!("jump to {} if false", t),
+        Node::Catch(t) => format!("on error jump to {}", t),
+        Node::EndCatch => "end of error handler".to_owned(),
+        op => symbol(op).to_owned(),
+    }
+}
+
+fn symbol(op: &Node) -> &'static str {
+    match op {
+        Node::Add => "+",
+        Node::Sub => "-",
+        Node::Mul => "*",
+        Node::Div => "/",
+        Node::Eq => "=",
+        Node::Ne => "<>",
+        Node::Lt => "<",
+        Node::Gt => ">",
+        Node::Le => "<=",
+        Node::Ge => ">=",
+        _ => "",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::model::calc::FormulaCache;
+
+    fn printed(expr: &str) -> String {
+        FormulaCache::default()
+            .compile(expr, (2, 3))
+            .print((2, 3))
+            .unwrap()
+    }
+
+    #[test]
+    fn canonical_form() {
+        assert_eq!(printed("sum( a1 : b2 ) + 1"), "SUM(A1:B2)+1");
+        assert_eq!(printed("((1+2))*3"), "(1+2)*3");
+        assert_eq!(printed("1-(2-3)"), "1-(2-3)");
+        assert_eq!(printed("(1-2)-3"), "1-2-3");
+        assert_eq!(printed("-(A1+1)"), "-(A1+1)");
+        assert_eq!(printed("(A1+1)%"), "(A1+1)%");
+        assert_eq!(
+            printed("regexmatch(A1, \"say \"\"hi\"\"\")"),
+            "REGEXMATCH(A1,\"say \"\"hi\"\"\")"
+        );
+    }
+
+    #[test]
+    fn round_trips() {
+        for expr in [
+            "1+2*3",
+            "(1+2)*3",
+            "1-(2+3)",
+            "2/(3*4)",
+            "-$A1*B$2",
+            "IF(A1>1,\"a\",\"b\")",
+            "SUM(A1:C3,$B$2)-1.50",
+            "1+(2=3)",
+            "(1<2)=TRUE",
+        ] {
+            let once = printed(expr);
+            assert_eq!(printed(&once), once, "{}", expr);
+        }
+    }
+}
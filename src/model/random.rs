@@ -0,0 +1,67 @@
+use rust_decimal::Decimal;
+
+/// A small splitmix64 generator, good enough for simulations and cheap to
+/// derive deterministically from a seed
+#[derive(Debug, Clone)]
+pub struct Rng {
+    state: u64,
+}
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        Rng { state: seed }
+    }
+
+    /// Derives an independent generator by mixing `v` into the state
+    pub fn fork(&self, v: u64) -> Self {
+        let mut rng = Rng::new(self.state ^ v);
+        rng.state = rng.next_u64();
+        rng
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    }
+
+    /// A decimal in `[0, 1)` with 15 digits
+    pub fn next_decimal(&mut self) -> Decimal {
+        Decimal::new((self.next_u64() % 1_000_000_000_000_000) as i64, 15)
+    }
+
+    /// An integer in `low..=high`
+    pub fn between(&mut self, low: i64, high: i64) -> i64 {
+        let span = (high as i128 - low as i128 + 1) as u128;
+        (low as i128 + (self.next_u64() as u128 % span) as i128) as i64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn same_seed_same_numbers() {
+        let draw = |seed| {
+            let mut rng = Rng::new(seed).fork(3);
+            (0..5).map(|_| rng.next_u64()).collect::<Vec<_>>()
+        };
+        assert_eq!(draw(42), draw(42));
+        assert_ne!(draw(42), draw(43));
+    }
+
+    #[test]
+    fn ranges() {
+        let mut rng = Rng::new(7);
+        for _ in 0..1000 {
+            let d = rng.next_decimal();
+            assert!(d >= Decimal::ZERO && d < Decimal::ONE);
+            assert!((-2..=2).contains(&rng.between(-2, 2)));
+        }
+        assert_eq!(rng.between(i64::MIN, i64::MIN), i64::MIN);
+        rng.between(i64::MIN, i64::MAX);
+    }
+}
@@ -0,0 +1,218 @@
+use std::fmt::Display;
+
+use super::sheet::{COLUMNS, ROWS};
+
+/// Written in formulas for references to deleted cells
+pub const INVALID_REFERENCE: &str = "#REF!";
+
+/// A rectangular block of cells, `from` is the top left and `to` the bottom
+/// right corner, both inclusive
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct Range {
+    pub from: (usize, usize),
+    pub to: (usize, usize),
+}
+
+impl Range {
+    /// Creates the range spanned by two opposite corners
+    pub fn new(a: (usize, usize), b: (usize, usize)) -> Self {
+        Range {
+            from: (a.0.min(b.0), a.1.min(b.1)),
+            to: (a.0.max(b.0), a.1.max(b.1)),
+        }
+    }
+
+    pub fn cell(pos: (usize, usize)) -> Self {
+        Range { from: pos, to: pos }
+    }
+
+    pub fn width(&self) -> usize {
+        self.to.0 - self.from.0 + 1
+    }
+
+    pub fn height(&self) -> usize {
+        self.to.1 - self.from.1 + 1
+    }
+
+    pub fn is_cell(&self) -> bool {
+        self.from == self.to
+    }
+
+    pub fn contains(&self, pos: (usize, usize)) -> bool {
+        (self.from.0..=self.to.0).contains(&pos.0) && (self.from.1..=self.to.1).contains(&pos.1)
+    }
+}
+
+impl Display for Range {
+    /// Writes `B2` for a single cell and `A1:C3` otherwise
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}{}", column_name(self.from.0), self.from.1 + 1)?;
+        if !self.is_cell() {
+            write!(f, ":{}{}", column_name(self.to.0), self.to.1 + 1)?;
+        }
+        Ok(())
+    }
+}
+
+/// Turns a column name like `A` or `AB` into its index, `None` past the
+/// last column of the sheet
+pub fn column_index(letters: &str) -> Option<usize> {
+    if letters.is_empty() || letters.len() > 3 {
+        return None;
+    }
+    letters
+        .chars()
+        .try_fold(0, |acc, ch| {
+            let ch = ch.to_ascii_uppercase();
+            ch.is_ascii_uppercase()
+                .then(|| acc * 26 + (ch as usize - 'A' as usize + 1))
+        })
+        .map(|i| i - 1)
+        .filter(|x| *x < COLUMNS)
+}
+
+/// Turns a column index into its name, the inverse of [column_index]
+pub fn column_name(mut x: usize) -> String {
+    let mut name = vec![];
+    loop {
+        name.push((b'A' + (x % 26) as u8) as char);
+        if x < 26 {
+            break;
+        }
+        x = x / 26 - 1;
+    }
+    name.iter().rev().collect()
+}
+
+/// Parses a cell name such as `B12`, or `$B$12` with the markers of an
+/// absolute reference
+pub fn parse_cell(s: &str) -> Option<(usize, usize)> {
+    let s = s.strip_prefix('$').unwrap_or(s);
+    let split = s.find(|c: char| !c.is_ascii_alphabetic())?;
+    let (letters, digits) = s.split_at(split);
+    let digits = digits.strip_prefix('$').unwrap_or(digits);
+    let row: usize = digits.parse().ok()?;
+    if row == 0 || row > ROWS || !digits.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    Some((column_index(letters)?, row - 1))
+}
+
+/// Parses either a single cell or a range such as `A1:B3`
+pub fn parse_range(s: &str) -> Option<Range> {
+    match s.split_once(':') {
+        Some((a, b)) => Some(Range::new(parse_cell(a.trim())?, parse_cell(b.trim())?)),
+        None => parse_cell(s.trim()).map(Range::cell),
+    }
+}
+
+/// One coordinate of a reference in a formula
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Hash)]
+pub enum Coord {
+    /// An offset from the formula's own cell, so copies of the formula refer
+    /// to the cells at the same distance
+    Relative(isize),
+    /// A fixed column or row, written with a `$` in front
+    Absolute(usize),
+    /// A column or row that was deleted
+    Invalid,
+}
+
+impl Coord {
+    fn new(target: usize, absolute: bool, origin: usize) -> Self {
+        match absolute {
+            true => Coord::Absolute(target),
+            false => Coord::Relative(target as isize - origin as isize),
+        }
+    }
+
+    /// The column or row referred to from `origin`, `None` if it is before
+    /// the first one
+    pub fn resolve(&self, origin: usize) -> Option<usize> {
+        match *self {
+            Coord::Relative(offset) => origin.checked_add_signed(offset),
+            Coord::Absolute(i) => Some(i),
+            Coord::Invalid => None,
+        }
+    }
+}
+
+/// A cell as written in a formula, independent of where the formula is
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Hash)]
+pub struct CellRef {
+    pub x: Coord,
+    pub y: Coord,
+}
+
+impl CellRef {
+    /// A reference to a cell that was deleted
+    pub const INVALID: CellRef = CellRef {
+        x: Coord::Invalid,
+        y: Coord::Invalid,
+    };
+
+    /// The reference to `target` written in the cell at `origin`, `absolute`
+    /// tells for the column and the row whether they were marked with `$`
+    pub fn new(target: (usize, usize), absolute: (bool, bool), origin: (usize, usize)) -> Self {
+        CellRef {
+            x: Coord::new(target.0, absolute.0, origin.0),
+            y: Coord::new(target.1, absolute.1, origin.1),
+        }
+    }
+
+    pub fn resolve(&self, origin: (usize, usize)) -> Option<(usize, usize)> {
+        Some((self.x.resolve(origin.0)?, self.y.resolve(origin.1)?))
+    }
+
+    /// A reference to `target` from the cell at `origin`, with the column and
+    /// row absolute where they are in this one
+    pub fn pointed_at(&self, target: (usize, usize), origin: (usize, usize)) -> Self {
+        let absolute = |c: Coord| matches!(c, Coord::Absolute(_));
+        CellRef::new(target, (absolute(self.x), absolute(self.y)), origin)
+    }
+
+    /// The reference as written in the cell at `origin`, such as `$B12`,
+    /// `None` if it points outside of the sheet from there
+    pub fn name(&self, origin: (usize, usize)) -> Option<String> {
+        let (x, y) = self.resolve(origin)?;
+        let dollar = |c: Coord| match c {
+            Coord::Absolute(_) => "$",
+            _ => "",
+        };
+        Some(format!(
+            "{}{}{}{}",
+            dollar(self.x),
+            column_name(x),
+            dollar(self.y),
+            y + 1
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn column_names() {
+        for (name, x) in [("A", 0), ("Z", 25), ("AA", 26), ("XFD", COLUMNS - 1)] {
+            assert_eq!(column_index(name), Some(x));
+            assert_eq!(column_name(x), name);
+        }
+        assert_eq!(column_index("xfe"), None);
+        assert_eq!(column_index("ZZZ"), None);
+        assert_eq!(column_index(""), None);
+    }
+
+    #[test]
+    fn cell_names() {
+        assert_eq!(parse_cell("B12"), Some((1, 11)));
+        assert_eq!(parse_cell("$B$12"), Some((1, 11)));
+        assert_eq!(parse_cell("B$12"), Some((1, 11)));
+        assert_eq!(parse_cell("XFD1048576"), Some((COLUMNS - 1, ROWS - 1)));
+        for bad in ["B0", "B", "12", "B+1", "XFD1048577", "$$B1"] {
+            assert_eq!(parse_cell(bad), None, "{}", bad);
+        }
+        assert_eq!(parse_range(" C3 : A1 "), Some(Range::new((0, 0), (2, 2))));
+    }
+}
@@ -0,0 +1,52 @@
+use rust_decimal::{Decimal, RoundingStrategy};
+
+/// Workbook wide options that change how formulas are evaluated
+#[derive(Debug, Clone, Default)]
+pub struct Settings {
+    /// Let integers grow past `i64` instead of failing with an overflow
+    pub big_int: bool,
+    /// How ties are broken by ROUND, MROUND and currency display
+    pub rounding: Rounding,
+    /// Seed for RAND and RANDBETWEEN, setting it again replays the same values
+    pub seed: u64,
+    pub iteration: Iteration,
+}
+
+/// How circular references are evaluated
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Iteration {
+    /// Evaluate cycles repeatedly instead of failing them as cyclic
+    pub enabled: bool,
+    /// Cycles still changing after this many rounds fail as not converged
+    pub max_iterations: u32,
+    /// A cycle is stable once no value changes by more than this in a round
+    pub tolerance: Decimal,
+}
+
+impl Default for Iteration {
+    fn default() -> Self {
+        Iteration {
+            enabled: false,
+            max_iterations: 100,
+            tolerance: Decimal::new(1, 3),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum Rounding {
+    /// 2.5 rounds to 3 and -2.5 to -3
+    #[default]
+    HalfAwayFromZero,
+    /// Banker's rounding, 2.5 rounds to 2 and 3.5 to 4
+    HalfEven,
+}
+
+impl Rounding {
+    pub fn strategy(&self) -> RoundingStrategy {
+        match self {
+            Rounding::HalfAwayFromZero => RoundingStrategy::MidpointAwayFromZero,
+            Rounding::HalfEven => RoundingStrategy::MidpointNearestEven,
+        }
+    }
+}
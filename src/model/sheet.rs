@@ -1,23 +1,90 @@
-use std::{fmt::Display, ops::{IndexMut, Index}, collections::{HashMap, HashSet}, rc::Rc};
+use std::{
+    cmp::Ordering,
+    collections::{BTreeMap, HashMap, HashSet},
+    fmt::Display,
+    rc::Rc,
+    time::{SystemTime, UNIX_EPOCH},
+};
 
-use super::calc::{parse, ExecutionError, ByteCode};
+use rust_decimal::Decimal;
+
+use super::{
+    calc::{ByteCode, Context, ExecutionError, FormulaCache, Trace},
+    filter::AutoFilter,
+    format::Format,
+    function::Function,
+    graph::Graph,
+    number::Number,
+    reference::Range,
+    settings::{Iteration, Rounding, Settings},
+    shift::{Axis, Move, Relocation, Reorder, Shift},
+    value::Value,
+    whatif::Scenarios,
+};
 
 #[derive(Debug, Clone)]
 pub struct Expression {
     text: String,
-    run: ByteCode,
+    /// Shared by all cells with the same formula relative to their position
+    run: Rc<ByteCode>,
 }
 
 impl Expression {
-    pub fn new(text: String) -> Self {
-        let run = parse(&text[1..]);
-        Expression { text, run }
+    /// Compiles `text` written in the cell at `pos`, the text is kept in
+    /// canonical form unless it does not parse
+    pub fn new(text: String, pos: (usize, usize), formulas: &mut FormulaCache) -> Self {
+        let run = formulas.compile(&text[1..], pos);
+        match run.print(pos) {
+            Some(canonical) => Expression {
+                text: format!("={}", canonical),
+                run,
+            },
+            None => Expression { text, run },
+        }
     }
 
-    /// Get the cells this expression depends on for the position of `self_pos`
-    pub fn deps(&self, self_pos: Pos) -> Vec<Pos> {
+    /// Get the cells and ranges this expression depends on for the position
+    /// of `self_pos`
+    pub fn deps(&self, self_pos: (usize, usize)) -> Vec<Range> {
         self.run.deps(self_pos)
     }
+
+    /// The expression copied to `to`, with relative references pointing at
+    /// the cells at the same distance from there
+    pub fn copied(&self, to: (usize, usize), formulas: &mut FormulaCache) -> Self {
+        match self.run.print(to) {
+            Some(text) => Expression::new(format!("={}", text), to, formulas),
+            None => self.clone(),
+        }
+    }
+
+    pub fn calls(&self, function: Function) -> bool {
+        self.run.calls(function)
+    }
+
+    /// Like [Expression::copied], with the rows and columns of references
+    /// swapped
+    pub fn transposed(&self, to: (usize, usize), formulas: &mut FormulaCache) -> Self {
+        match self.run.transposed(to) {
+            Some(text) => Expression::new(format!("={}", text), to, formulas),
+            None => self.clone(),
+        }
+    }
+
+    /// The expression moved from `from` to `to` by `shift`, with its
+    /// references following the cells they point at
+    pub fn shifted(
+        &self,
+        from: (usize, usize),
+        to: (usize, usize),
+        shift: &impl Relocation,
+        formulas: &mut FormulaCache,
+    ) -> Self {
+        match self.run.shifted(from, to, shift) {
+            Some(text) => Expression::new(format!("={}", text), to, formulas),
+            None => self.clone(),
+        }
+    }
 }
 
 impl Display for Expression {
@@ -29,9 +96,9 @@ impl Display for Expression {
 #[derive(Debug, Clone)]
 pub enum Cell {
     None,
-    Val(i64),
+    Val(Number),
     String(String),
-    Expression(Rc<Expression>, Result<i64, ExecutionError>),
+    Expression(Rc<Expression>, Result<Value, ExecutionError>),
 }
 
 impl Cell {
@@ -53,12 +120,36 @@ impl Cell {
         }
     }
 
-    pub fn val(&self) -> Option<i64> {
+    /// A plain cell holding the value of this one, errors become text
+    pub fn computed(&self) -> Cell {
+        match self.result() {
+            Some(Ok(Value::Number(n))) => Cell::Val(n),
+            Some(Ok(Value::Text(s))) => Cell::String(s),
+            Some(Ok(v @ Value::Bool(_))) => Cell::String(v.to_string()),
+            Some(Err(_)) => Cell::String("#Error".to_owned()),
+            None => Cell::None,
+        }
+    }
+
+    pub fn val(&self) -> Option<Number> {
         match self {
             Cell::None => None,
-            Cell::Val(v) => Some(*v),
+            Cell::Val(v) => Some(v.clone()),
             Cell::String(_) => None,
-            Cell::Expression(_, r) => r.ok(),
+            Cell::Expression(_, r) => match r {
+                Ok(Value::Number(n)) => Some(n.clone()),
+                _ => None,
+            },
+        }
+    }
+
+    /// What a formula sees when reading this cell, `None` if it is empty
+    pub fn result(&self) -> Option<Result<Value, ExecutionError>> {
+        match self {
+            Cell::None => None,
+            Cell::Val(v) => Some(Ok(Value::Number(v.clone()))),
+            Cell::String(s) => Some(Ok(Value::Text(s.clone()))),
+            Cell::Expression(_, r) => Some(r.clone()),
         }
     }
 }
@@ -75,96 +166,947 @@ impl Display for Cell {
                 } else {
                     write!(f, "#Error")
                 }
-            },
+            }
         }
     }
 }
 
-type Pos = (u16, u16);
+type Pos = (usize, usize);
+
+/// A change to the sheet, recorded as what has to be applied to undo it
+#[derive(Debug, Clone)]
+pub enum Change {
+    /// Puts the cell back at its position
+    Cell(Pos, Cell),
+    Format(Pos, Format),
+    Width(usize, u16),
+    /// Shifts rows or columns back, the cells lost or rewritten by the
+    /// original shift are restored by the changes recorded before it
+    Shift(Shift),
+    /// Puts back the scenarios as they were before a shift
+    Scenarios(Scenarios),
+    /// Puts back the autofilter as it was before a shift
+    Filter(Option<AutoFilter>),
+}
+
+/// Number of columns cells can be placed in, `A` to `XFD`
+pub const COLUMNS: usize = 16384;
+/// Number of rows cells can be placed in
+pub const ROWS: usize = 1_048_576;
+/// Width of a column in characters unless set otherwise
+const DEFAULT_WIDTH: u16 = 7;
 
 #[derive(Debug)]
 pub struct Sheet {
-    pub fields: Vec<(u16, Vec<Cell>)>,
-    deps: HashMap<Pos, Vec<Pos>>
+    /// The non-empty cells, keyed by row and then column so they are
+    /// visited in reading order
+    cells: BTreeMap<(usize, usize), Cell>,
+    /// Widths of the columns not as wide as [DEFAULT_WIDTH]
+    widths: HashMap<usize, u16>,
+    /// Given out for the empty cells
+    empty: Cell,
+    pub settings: Settings,
+    /// Compiled formulas, shared between the cells using them
+    pub formulas: FormulaCache,
+    pub scenarios: Scenarios,
+    formats: HashMap<Pos, Format>,
+    /// The references between cells, including the ones only found when
+    /// evaluating a formula such as the targets of INDIRECT
+    graph: Graph,
+    /// The cycle each formula failing with [ExecutionError::Cyclic] is part of
+    cycles: HashMap<Pos, Vec<Pos>>,
+    /// Cells calling volatile functions, these are recomputed on every
+    /// recalculation regardless of `graph`
+    volatile: HashSet<Pos>,
+    /// Number of recalculations since the seed was last set
+    generation: u64,
+    filter: Option<AutoFilter>,
+    /// The rows `filter` hides from view
+    hidden: HashSet<usize>,
+    /// How to undo every change since the last [Sheet::take_changes], in
+    /// the order they were made
+    changes: Vec<Change>,
 }
 
 impl Sheet {
     pub fn new() -> Self {
-        Sheet { fields: vec![(7, vec![Cell::None; 30]); 10], deps: HashMap::new() }
+        Sheet {
+            cells: BTreeMap::new(),
+            widths: HashMap::new(),
+            empty: Cell::None,
+            settings: Settings {
+                seed: SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map_or(0, |d| d.as_nanos() as u64),
+                ..Settings::default()
+            },
+            formulas: FormulaCache::default(),
+            scenarios: Scenarios::default(),
+            formats: HashMap::new(),
+            graph: Graph::default(),
+            cycles: HashMap::new(),
+            volatile: HashSet::new(),
+            generation: 0,
+            filter: None,
+            hidden: HashSet::new(),
+            changes: vec![],
+        }
+    }
+
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    /// Sets the random seed and recalculates, giving the same random values
+    /// every time the same seed is set
+    pub fn set_seed(&mut self, seed: u64) {
+        self.settings.seed = seed;
+        self.generation = 0;
+        self.recalculate();
+    }
+
+    /// Starts a new recalculation, recomputing every volatile cell and the
+    /// formulas depending on them
+    pub fn recalculate(&mut self) {
+        self.generation += 1;
+
+        let mut volatile: Vec<Pos> = self.volatile.iter().copied().collect();
+        volatile.sort();
+        self.recompute(self.in_order(&volatile));
+    }
+
+    /// The cell at `pos`, `None` if it is outside the sheet
+    pub fn get(&self, pos: (usize, usize)) -> Option<&Cell> {
+        if pos.0 >= COLUMNS || pos.1 >= ROWS {
+            return None;
+        }
+        Some(self.cells.get(&(pos.1, pos.0)).unwrap_or(&self.empty))
+    }
+
+    /// Every non-empty cell, row by row
+    pub fn cells(&self) -> impl Iterator<Item = ((usize, usize), &Cell)> {
+        self.cells.iter().map(|(&(y, x), c)| ((x, y), c))
+    }
+
+    /// The non-empty cells in `range`, row by row
+    pub fn cells_in(&self, range: Range) -> impl Iterator<Item = ((usize, usize), &Cell)> {
+        let Range { from, to } = range;
+        self.cells
+            .range((from.1, from.0)..=(to.1, to.0))
+            .filter(move |((_, x), _)| (from.0..=to.0).contains(x))
+            .map(|(&(y, x), c)| ((x, y), c))
+    }
+
+    /// Puts `cell` at `pos` without evaluating anything, empty cells take no
+    /// room
+    fn set(&mut self, pos: Pos, cell: Cell) {
+        match cell {
+            Cell::None => self.cells.remove(&(pos.1, pos.0)),
+            cell => self.cells.insert((pos.1, pos.0), cell),
+        };
+    }
+
+    /// The result of the formula at `pos`
+    fn result_mut(&mut self, pos: Pos) -> Option<&mut Result<Value, ExecutionError>> {
+        match self.cells.get_mut(&(pos.1, pos.0)) {
+            Some(Cell::Expression(_, r)) => Some(r),
+            _ => None,
+        }
+    }
+
+    pub fn column_width(&self, x: usize) -> u16 {
+        self.widths.get(&x).copied().unwrap_or(DEFAULT_WIDTH)
+    }
+
+    pub fn set_column_width(&mut self, x: usize, width: u16) {
+        self.changes.push(Change::Width(x, self.column_width(x)));
+        if width == DEFAULT_WIDTH {
+            self.widths.remove(&x);
+        } else {
+            self.widths.insert(x, width);
+        }
+    }
+
+    pub fn format(&self, pos: (usize, usize)) -> Format {
+        self.formats.get(&pos).copied().unwrap_or_default()
     }
 
-    /// Inserts and calculates the value of the `cell` at position `pos`
-    pub fn insert_cell(&mut self, mut cell: Cell, pos: (usize, usize)) {
-        // TODO: <- Remove old cell if it exists
-        if let Cell::Expression(ex, ref mut res) = &mut cell {
-            if self.is_cyclic((pos.0 as u16, pos.1 as u16)) {
-                *res = Err(ExecutionError::Cyclic);
-                self[pos.0][pos.1] = cell;
+    /// The cells in `range` with a format other than [Format::General],
+    /// empty or not
+    pub fn formats_in(&self, range: Range) -> impl Iterator<Item = ((usize, usize), Format)> + '_ {
+        self.formats
+            .iter()
+            .filter(move |(p, _)| range.contains(**p))
+            .map(|(p, f)| (*p, *f))
+    }
+
+    /// Sets the format of the cell at `pos` and converts its value to match
+    pub fn set_format(&mut self, format: Format, pos: (usize, usize)) {
+        self.set_formats(vec![(pos, format)]);
+    }
+
+    /// Sets the formats of several cells and converts their values to match
+    /// as one edit
+    pub fn set_formats(&mut self, formats: Vec<((usize, usize), Format)>) {
+        let mut cells = vec![];
+        for (pos, format) in formats {
+            self.changes.push(Change::Format(pos, self.format(pos)));
+            if format == Format::General {
+                self.formats.remove(&pos);
             } else {
-                for d in ex.deps((pos.0 as u16, pos.1 as u16)) {
-                    self.add_dependency(d, (pos.0 as u16, pos.1 as u16));
-                }
-                *res = ex.run.execute(&self);
-                self[pos.0][pos.1] = cell;
+                self.formats.insert(pos, format);
+            }
+            if let Some(cell) = self.cells.get(&(pos.1, pos.0)) {
+                cells.push((pos, cell.clone()));
             }
         }
+        self.insert_cells(cells);
     }
 
-    fn is_cyclic(&self, pos: Pos) -> bool {
-        let mut next: Vec<Pos> = vec![];
-        let mut been_at: HashSet<Pos> = HashSet::new();
+    pub fn filter(&self) -> Option<&AutoFilter> {
+        self.filter.as_ref()
+    }
+
+    /// Replaces the autofilter and hides the rows not matching it
+    pub fn set_filter(&mut self, filter: Option<AutoFilter>) {
+        self.filter = filter;
+        self.refilter();
+    }
+
+    /// Applies the autofilter again to the current values. The formulas
+    /// calling SUBTOTAL are recomputed when other rows end up hidden.
+    pub fn refilter(&mut self) {
+        let hidden = self
+            .filter
+            .as_ref()
+            .map(|f| f.hidden(self))
+            .unwrap_or_default();
+        if hidden == self.hidden {
+            return;
+        }
+        self.hidden = hidden;
+        let subtotals: Vec<Pos> = self
+            .cells()
+            .filter(|(_, c)| matches!(c, Cell::Expression(ex, _) if ex.calls(Function::Subtotal)))
+            .map(|(p, _)| p)
+            .collect();
+        self.recompute(self.in_order(&subtotals));
+    }
+
+    /// Whether the row `y` is hidden by the autofilter
+    pub fn is_hidden(&self, y: usize) -> bool {
+        self.hidden.contains(&y)
+    }
+
+    /// Writes the value at `pos` as it is shown in the sheet
+    pub fn display(&self, pos: (usize, usize), f: &mut impl std::fmt::Write) -> std::fmt::Result {
+        match self.get(pos) {
+            Some(cell) => self.format(pos).write_cell(cell, self.settings.rounding, f),
+            None => Ok(()),
+        }
+    }
+
+    /// Runs `ex` as if it was placed at `pos`, also returning every cell and
+    /// range it read
+    fn evaluate(
+        &self,
+        ex: &Expression,
+        pos: (usize, usize),
+    ) -> (Result<Value, ExecutionError>, Vec<Range>) {
+        let format = self.format(pos);
+        let mut ctx = Context::new(self, pos);
+        let res = ex.run.execute(&mut ctx).map(|v| match v {
+            Value::Number(n) => Value::Number(format.coerce(n)),
+            v => v,
+        });
+        (res, ctx.reads)
+    }
 
-        if let Some(xc) = self.deps.get(&pos) {
-            for i in xc {
-                next.push(*i);
+    /// The cells the formula at `pos` reads from, including the ones only
+    /// found when evaluating it. Of ranges only the non-empty cells count.
+    pub fn precedents(&self, pos: (usize, usize)) -> Vec<(usize, usize)> {
+        let mut cells = vec![];
+        for r in self.graph.precedents(pos) {
+            match r.is_cell() {
+                true => cells.push(r.from),
+                false => cells.extend(self.cells_in(*r).map(|(p, _)| p)),
             }
         }
+        cells.sort();
+        cells.dedup();
+        cells
+    }
+
+    /// The formulas reading from the cell at `pos`
+    pub fn dependents(&self, pos: (usize, usize)) -> Vec<(usize, usize)> {
+        let mut cells = self.graph.dependents(pos);
+        cells.sort();
+        cells
+    }
 
-        while let Some(this) = next.pop() {
-            if this == pos {
-                return true;
+    /// The cycle of references the formula at `pos` is part of if it failed
+    /// because of one, from `pos` through the formulas reading from it back
+    /// to `pos`
+    pub fn cycle(&self, pos: (usize, usize)) -> Option<&[(usize, usize)]> {
+        self.cycles.get(&pos).map(|c| c.as_slice())
+    }
+
+    /// Evaluates the formula at `pos` again, recording every step
+    pub fn trace(&self, pos: (usize, usize)) -> Option<Trace> {
+        match self.get(pos)? {
+            Cell::Expression(ex, _) => Some(ex.run.trace(&mut Context::new(self, pos))),
+            _ => None,
+        }
+    }
+
+    /// Recomputes the expression at `pos` and updates what it reads from
+    fn compute(&mut self, pos: (usize, usize)) {
+        if let Some(Cell::Expression(ex, _)) = self.get(pos) {
+            let ex = ex.clone();
+            let (res, reads) = self.evaluate(&ex, pos);
+            if let Some(r) = self.result_mut(pos) {
+                *r = res;
             }
+            // Whole ranges found at run time are kept, so a formula summing
+            // INDIRECT("A1:A3") also follows the cells of it that are empty.
+            // What a range of the formula covers is already known.
+            let mut precedents = ex.deps(pos);
+            let dynamic: Vec<Range> = reads
+                .into_iter()
+                .filter(|d| {
+                    !precedents
+                        .iter()
+                        .any(|r| r.contains(d.from) && r.contains(d.to))
+                })
+                .collect();
+            precedents.extend(dynamic);
+            self.graph.set_precedents(pos, precedents);
+        }
+    }
 
-            been_at.insert(this);
+    /// Computes the formulas among `cells` in the given order. Formulas on a
+    /// cycle fail unless iterative calculation is enabled, in which case the
+    /// cycle is left to settle.
+    fn recompute(&mut self, (cells, cyclic): (Vec<(usize, usize)>, bool)) {
+        let mut on_cycles = vec![];
+        for &pos in &cells {
+            let cycle = if cyclic { self.graph.cycle(pos) } else { None };
+            match cycle {
+                Some(path) if !self.settings.iteration.enabled => {
+                    if let Some(r) = self.result_mut(pos) {
+                        *r = Err(ExecutionError::Cyclic);
+                    }
+                    self.cycles.insert(pos, path);
+                }
+                cycle => {
+                    if cycle.is_some() {
+                        on_cycles.push(pos);
+                    }
+                    self.cycles.remove(&pos);
+                    self.compute(pos);
+                }
+            }
+        }
+        // Every cycle is left to settle, unless it was already iterated
+        // with an earlier one it depends on
+        let mut iterated = HashSet::new();
+        for pos in on_cycles {
+            if !iterated.contains(&pos) {
+                iterated.extend(self.iterate(pos));
+            }
+        }
+    }
 
-            if let Some(xc) = self.deps.get(&this) {
-                for i in xc {
-                    if !been_at.contains(i) {
-                        next.push(*i);
+    /// The cells of `roots` together with every cell depending on them
+    /// directly or indirectly, ordered so every cell comes after the ones it
+    /// depends on as far as there is no cycle among them, and whether there is
+    fn in_order(&self, roots: &[(usize, usize)]) -> (Vec<(usize, usize)>, bool) {
+        // Depth first, a cell is finished once all of its dependents are.
+        // Reaching a cell that is seen but not finished means going around.
+        let mut finished = vec![];
+        let mut done = HashSet::new();
+        let mut seen = HashSet::new();
+        let mut cyclic = false;
+        for &root in roots {
+            if !seen.insert(root) {
+                continue;
+            }
+            let mut stack = vec![(root, self.graph.dependents(root).into_iter())];
+            while let Some((p, next)) = stack.last_mut() {
+                match next.next() {
+                    Some(d) => {
+                        if seen.insert(d) {
+                            stack.push((d, self.graph.dependents(d).into_iter()));
+                        } else if !done.contains(&d) {
+                            cyclic = true;
+                        }
+                    }
+                    None => {
+                        let p = *p;
+                        stack.pop();
+                        done.insert(p);
+                        finished.push(p);
                     }
                 }
             }
         }
 
-        false
+        finished.reverse();
+        (finished, cyclic)
     }
 
-    fn add_dependency(&mut self, target: Pos, depender: Pos) {
-        if let Some(this) = self.deps.get_mut(&target) {
-            this.push(depender);
-        } else {
-            self.deps.insert(target, vec![depender]);
+    /// Switches between `i64` and unbounded integer arithmetic and
+    /// recomputes every expression with the new mode
+    pub fn set_big_int(&mut self, big_int: bool) {
+        self.settings.big_int = big_int;
+        self.recompute_formulas();
+    }
+
+    /// Changes how halves are rounded and recomputes every expression, as
+    /// rounding functions depend on it
+    pub fn set_rounding(&mut self, rounding: Rounding) {
+        self.settings.rounding = rounding;
+        self.recompute_formulas();
+    }
+
+    /// Recomputes every expression after a setting they depend on changed
+    fn recompute_formulas(&mut self) {
+        let formulas: Vec<Pos> = self
+            .cells()
+            .filter(|(_, c)| matches!(c, Cell::Expression(_, _)))
+            .map(|(p, _)| p)
+            .collect();
+        self.recompute(self.in_order(&formulas));
+    }
+
+    /// Changes how circular references are evaluated and evaluates the ones
+    /// that failed before again
+    pub fn set_iteration(&mut self, iteration: Iteration) {
+        self.settings.iteration = iteration;
+
+        use ExecutionError::{Cyclic, NotConverged};
+        let failed: Vec<Pos> = self
+            .cells()
+            .filter(|(_, c)| matches!(c, Cell::Expression(_, Err(Cyclic | NotConverged))))
+            .map(|(p, _)| p)
+            .collect();
+        self.recompute(self.in_order(&failed));
+    }
+
+    /// Makes a recorded change, recording how to undo it in turn
+    pub fn apply(&mut self, change: Change) {
+        match change {
+            Change::Cell(pos, cell) => self.insert_cell(cell, pos),
+            Change::Format(pos, format) => self.set_format(format, pos),
+            Change::Width(x, width) => self.set_column_width(x, width),
+            Change::Shift(shift) => {
+                self.shift(shift);
+            }
+            Change::Scenarios(scenarios) => {
+                let before = std::mem::replace(&mut self.scenarios, scenarios);
+                self.changes.push(Change::Scenarios(before));
+            }
+            Change::Filter(filter) => {
+                self.changes.push(Change::Filter(self.filter.clone()));
+                self.set_filter(filter);
+            }
         }
     }
 
-    fn remove_dependency(&mut self, target: Pos, depender: Pos) {
-        if let Some(this) = self.deps.get_mut(&target) {
-            let index = this.iter().position(|x| *x == depender).unwrap();
-            this.remove(index);
+    /// How to undo the changes made since the last call, in the order they
+    /// were made
+    pub fn take_changes(&mut self) -> Vec<Change> {
+        std::mem::take(&mut self.changes)
+    }
+
+    /// Inserts or deletes rows or columns, moving the cells after them and
+    /// rewriting every formula to keep pointing at the same cells. Fails
+    /// without changing anything if cells would be pushed off the sheet.
+    pub fn shift(&mut self, shift: Shift) -> bool {
+        // Only deleted cells are gone, inserting never drops any
+        let fits = |p: Pos| match shift.pos(p) {
+            Some((x, y)) => x < COLUMNS && y < ROWS,
+            None => shift.delete,
+        };
+        if !self.cells().all(|(p, _)| fits(p)) {
+            return false;
+        }
+
+        // Undone by shifting back first and then restoring the deleted cells,
+        // the formulas as they were written before, the scenarios and the
+        // autofilter
+        self.changes.push(Change::Scenarios(self.scenarios.clone()));
+        self.changes.push(Change::Filter(self.filter.clone()));
+        let restore: Vec<Change> = self
+            .cells()
+            .filter(|(p, c)| matches!(c, Cell::Expression(_, _)) || shift.pos(*p).is_none())
+            .map(|(p, c)| Change::Cell(p, c.clone()))
+            .chain(
+                self.formats
+                    .iter()
+                    .filter(|(p, _)| shift.pos(**p).is_none())
+                    .map(|(p, f)| Change::Format(*p, *f)),
+            )
+            .chain(
+                self.widths
+                    .iter()
+                    .filter(|(x, _)| shift.axis == Axis::Column && shift.index(**x).is_none())
+                    .map(|(x, w)| Change::Width(*x, *w)),
+            )
+            .collect();
+        self.changes.extend(restore);
+        self.changes.push(Change::Shift(shift.inverse()));
+
+        let mut formulas = vec![];
+        self.graph = Graph::default();
+        for ((y, x), cell) in std::mem::take(&mut self.cells) {
+            let Some(to) = shift.pos((x, y)) else {
+                continue;
+            };
+            let cell = match cell {
+                Cell::Expression(ex, r) => {
+                    let ex = ex.shifted((x, y), to, &shift, &mut self.formulas);
+                    self.graph.set_precedents(to, ex.deps(to));
+                    formulas.push(to);
+                    Cell::Expression(Rc::new(ex), r)
+                }
+                cell => cell,
+            };
+            self.set(to, cell);
+        }
+
+        let moved = |p: Pos| shift.pos(p);
+        self.formats = std::mem::take(&mut self.formats)
+            .into_iter()
+            .filter_map(|(p, f)| Some((moved(p)?, f)))
+            .collect();
+        self.volatile = std::mem::take(&mut self.volatile)
+            .into_iter()
+            .filter_map(moved)
+            .collect();
+        if shift.axis == Axis::Column {
+            self.widths = std::mem::take(&mut self.widths)
+                .into_iter()
+                .filter_map(|(x, w)| Some((shift.index(x)?, w)))
+                .collect();
         }
+        self.cycles.clear();
+        self.scenarios.shift(&shift);
+        self.filter = self.filter.as_ref().and_then(|f| f.shifted(&shift));
+
+        self.recompute(self.in_order(&formulas));
+        self.recalculate();
+        self.refilter();
+        true
+    }
+
+    /// Moves a block of cells with their formats, replacing the cells where it
+    /// lands. Formulas in and outside of the block keep pointing at the
+    /// same cells. Fails if the block would not fit on the sheet.
+    pub fn move_cells(&mut self, mv: Move) -> bool {
+        let target = mv.target();
+        if target.to.0 >= COLUMNS || target.to.1 >= ROWS {
+            return false;
+        }
+
+        let formulas: Vec<(Pos, Rc<Expression>, Result<Value, ExecutionError>)> = self
+            .cells()
+            .filter(|(p, _)| !mv.source.contains(*p) && !target.contains(*p))
+            .filter_map(|(p, c)| match c {
+                Cell::Expression(ex, r) => Some((p, ex.clone(), r.clone())),
+                _ => None,
+            })
+            .collect();
+        let block: Vec<(Pos, Cell)> = self
+            .cells_in(mv.source)
+            .map(|(p, c)| (p, c.clone()))
+            .collect();
+        let formats: Vec<(Pos, Format)> = self
+            .formats
+            .iter()
+            .filter(|(p, _)| mv.source.contains(**p))
+            .map(|(p, f)| (*p, *f))
+            .collect();
+
+        // Empties the source and the target before filling the target
+        let mut formatted = vec![];
+        let mut cells = vec![];
+        for area in [mv.source, target] {
+            cells.extend(self.cells_in(area).map(|(p, _)| (p, Cell::None)));
+            formatted.extend(
+                self.formats
+                    .keys()
+                    .filter(|p| area.contains(**p))
+                    .map(|p| (*p, Format::General)),
+            );
+        }
+        formatted.extend(formats.into_iter().map(|(p, f)| (mv.pos(p).unwrap(), f)));
+        self.set_formats(formatted);
+
+        for (p, cell) in block {
+            let to = mv.pos(p).unwrap();
+            let cell = match cell {
+                Cell::Expression(ex, r) => {
+                    Cell::Expression(Rc::new(ex.shifted(p, to, &mv, &mut self.formulas)), r)
+                }
+                cell => cell,
+            };
+            cells.push((to, cell));
+        }
+        for (p, ex, r) in formulas {
+            let moved = ex.shifted(p, p, &mv, &mut self.formulas);
+            if moved.to_string() != ex.to_string() {
+                cells.push((p, Cell::Expression(Rc::new(moved), r)));
+            }
+        }
+        self.insert_cells(cells);
+        true
+    }
+
+    /// Puts the rows of a range in a new order with their formats. Formulas
+    /// in the range keep pointing at the cells they read, following them to
+    /// their new rows, formulas outside of it still read the same places.
+    pub fn reorder(&mut self, reorder: &Reorder) {
+        let block: Vec<(Pos, Cell)> = self
+            .cells_in(reorder.range)
+            .map(|(p, c)| (p, c.clone()))
+            .collect();
+        let formats: Vec<(Pos, Format)> = self
+            .formats
+            .iter()
+            .filter(|(p, _)| reorder.range.contains(**p))
+            .map(|(p, f)| (*p, *f))
+            .collect();
+
+        let mut formatted: Vec<(Pos, Format)> =
+            formats.iter().map(|(p, _)| (*p, Format::General)).collect();
+        formatted.extend(
+            formats
+                .into_iter()
+                .map(|(p, f)| (reorder.pos(p).unwrap(), f)),
+        );
+        self.set_formats(formatted);
+
+        let mut cells: Vec<(Pos, Cell)> = block.iter().map(|(p, _)| (*p, Cell::None)).collect();
+        for (p, cell) in block {
+            let to = reorder.pos(p).unwrap();
+            let cell = match cell {
+                Cell::Expression(ex, r) => {
+                    Cell::Expression(Rc::new(ex.shifted(p, to, reorder, &mut self.formulas)), r)
+                }
+                cell => cell,
+            };
+            cells.push((to, cell));
+        }
+        self.insert_cells(cells);
+        self.refilter();
+    }
+
+    /// Inserts and calculates the value of the `cell` at position `pos`,
+    /// replacing what the cell there read from and recomputing every formula
+    /// depending on it. Cells outside the sheet are ignored.
+    pub fn insert_cell(&mut self, cell: Cell, pos: (usize, usize)) {
+        self.insert_cells(vec![(pos, cell)]);
+    }
+
+    /// Inserts several cells as one edit, like [Sheet::insert_cell] but
+    /// recomputing the formulas depending on them only once they are all in
+    /// place. A later cell at the same position replaces an earlier one.
+    pub fn insert_cells(&mut self, cells: Vec<((usize, usize), Cell)>) {
+        self.write(cells);
+        self.recalculate();
+    }
+
+    /// Inserts cells like [Sheet::insert_cells] without starting a new
+    /// recalculation, so volatile functions keep their values while inputs
+    /// are tried out
+    pub fn try_cells(&mut self, cells: Vec<((usize, usize), Cell)>) {
+        self.write(cells);
+    }
+
+    /// Puts the cells in place and recomputes what depends on them
+    fn write(&mut self, cells: Vec<((usize, usize), Cell)>) {
+        let mut written = vec![];
+        for (pos, cell) in cells {
+            let Some(before) = self.get(pos).cloned() else {
+                continue;
+            };
+            self.changes.push(Change::Cell(pos, before));
+            self.volatile.remove(&pos);
+            match &cell {
+                Cell::Expression(ex, _) => {
+                    if ex.run.is_volatile() {
+                        self.volatile.insert(pos);
+                    }
+                    self.graph.set_precedents(pos, ex.deps(pos));
+                }
+                _ => self.graph.remove(pos),
+            }
+
+            let cell = match cell {
+                Cell::Val(v) => Cell::Val(self.format(pos).coerce(v)),
+                cell => cell,
+            };
+            self.set(pos, cell);
+            written.push(pos);
+        }
+        self.recompute(self.in_order(&written));
+    }
+
+    /// Evaluates the cycle through `pos` and everything depending on it
+    /// round after round until the values settle within the tolerance.
+    /// Returns the cells evaluated.
+    fn iterate(&mut self, pos: (usize, usize)) -> Vec<Pos> {
+        let mut cells = vec![pos];
+        let mut seen = HashSet::from([pos]);
+        let mut i = 0;
+        while let Some(p) = cells.get(i) {
+            for d in self.graph.dependents(*p) {
+                if seen.insert(d) {
+                    cells.push(d);
+                }
+            }
+            i += 1;
+        }
+
+        // Every round starts from the values of the last one, the first one
+        // from zero where there is no value yet
+        for &p in &cells {
+            if let Some(r @ Err(_)) = self.result_mut(p) {
+                *r = Ok(Value::Number(Number::Int(0)));
+            }
+        }
+
+        let Iteration {
+            max_iterations,
+            tolerance,
+            ..
+        } = self.settings.iteration;
+        for _ in 0..max_iterations {
+            let mut settled = true;
+            for &p in &cells {
+                let before = self.get(p).and_then(|c| c.result());
+                self.compute(p);
+                let after = self.get(p).and_then(|c| c.result());
+                settled &= within(before, after, tolerance);
+            }
+            if settled {
+                return cells;
+            }
+        }
+
+        for &p in &cells {
+            if let Some(r) = self.result_mut(p) {
+                *r = Err(ExecutionError::NotConverged);
+            }
+        }
+        cells
     }
 }
 
-impl Index<usize> for Sheet {
-    type Output = Vec<Cell>;
+/// Whether a value changed by no more than `tolerance` in an iteration
+fn within(
+    before: Option<Result<Value, ExecutionError>>,
+    after: Option<Result<Value, ExecutionError>>,
+    tolerance: Decimal,
+) -> bool {
+    match (before, after) {
+        (Some(Ok(Value::Number(a))), Some(Ok(Value::Number(b)))) => a
+            .sub(&b, true)
+            .and_then(|d| d.to_decimal())
+            .is_ok_and(|d| d.abs() <= tolerance),
+        (Some(Ok(a)), Some(Ok(b))) => a.compare(&b) == Ordering::Equal,
+        (Some(Err(_)), Some(Err(_))) | (None, None) => true,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+impl Sheet {
+    /// Puts `text` into the cell at `pos` as if it was typed, as a formula,
+    /// a number or else as text
+    pub fn enter(&mut self, pos: (usize, usize), text: &str) {
+        let cell = match text {
+            "" => Cell::None,
+            t if t.starts_with('=') => {
+                let ex = Expression::new(t.to_owned(), pos, &mut self.formulas);
+                Cell::Expression(Rc::new(ex), Err(ExecutionError::NotExecuted))
+            }
+            t => t
+                .parse()
+                .map_or_else(|_| Cell::String(t.to_owned()), Cell::Val),
+        };
+        self.insert_cell(cell, pos);
+    }
+
+    /// What was typed into the cell at `pos`, formulas as printed back
+    pub fn entry(&self, pos: (usize, usize)) -> String {
+        let mut s = String::new();
+        self.get(pos).unwrap().entry(&mut s).unwrap();
+        s
+    }
 
-    fn index(&self, index: usize) -> &Self::Output {
-        &self.fields.index(index).1
+    /// The value at `pos` as it is shown in the sheet
+    pub fn shown(&self, pos: (usize, usize)) -> String {
+        let mut s = String::new();
+        self.display(pos, &mut s).unwrap();
+        s
     }
 }
 
-impl IndexMut<usize> for Sheet {
-    fn index_mut(&mut self, index: usize) -> &mut Self::Output {
-        &mut self.fields.index_mut(index).1
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn result(sheet: &Sheet, pos: Pos) -> Result<Value, ExecutionError> {
+        sheet.get(pos).unwrap().result().unwrap()
+    }
+
+    /// Whether the value at `pos` is within a thousandth of `expected`
+    fn near(sheet: &Sheet, pos: Pos, expected: i64) -> bool {
+        match result(sheet, pos) {
+            Ok(Value::Number(n)) => n
+                .sub(&Number::Int(expected), true)
+                .and_then(|d| d.to_decimal())
+                .is_ok_and(|d| d.abs() <= Decimal::new(1, 3)),
+            _ => false,
+        }
+    }
+
+    fn iterative() -> Iteration {
+        Iteration {
+            enabled: true,
+            ..Iteration::default()
+        }
+    }
+
+    #[test]
+    fn cycles_fail_with_their_path() {
+        let mut sheet = Sheet::new();
+        sheet.enter((0, 0), "=C1+1");
+        sheet.enter((1, 0), "=A1");
+        sheet.enter((2, 0), "=B1");
+        assert_eq!(result(&sheet, (0, 0)), Err(ExecutionError::Cyclic));
+        assert_eq!(
+            sheet.cycle((0, 0)),
+            Some(&[(0, 0), (1, 0), (2, 0), (0, 0)][..])
+        );
+        // Breaking the cycle clears it
+        sheet.enter((2, 0), "1");
+        assert_eq!(sheet.shown((1, 0)), "2");
+        assert_eq!(sheet.cycle((0, 0)), None);
+    }
+
+    #[test]
+    fn iteration_settles_cycles() {
+        let mut sheet = Sheet::new();
+        sheet.set_iteration(iterative());
+        sheet.enter((0, 0), "=B1/2+1");
+        sheet.enter((1, 0), "=A1");
+        assert!(near(&sheet, (0, 0), 2));
+        assert!(near(&sheet, (1, 0), 2));
+    }
+
+    #[test]
+    fn diverging_cycles_do_not_converge() {
+        let mut sheet = Sheet::new();
+        sheet.set_iteration(iterative());
+        sheet.enter((0, 0), "=B1+1");
+        sheet.enter((1, 0), "=A1");
+        assert_eq!(result(&sheet, (0, 0)), Err(ExecutionError::NotConverged));
+    }
+
+    #[test]
+    fn enabling_iteration_settles_every_failed_cycle() {
+        let mut sheet = Sheet::new();
+        sheet.enter((0, 0), "=B1/2+1");
+        sheet.enter((1, 0), "=A1");
+        sheet.enter((0, 5), "=B6/2+2");
+        sheet.enter((1, 5), "=A6");
+        assert_eq!(result(&sheet, (0, 5)), Err(ExecutionError::Cyclic));
+
+        sheet.set_iteration(iterative());
+        assert!(near(&sheet, (0, 0), 2));
+        // Not only the first cycle found
+        assert!(near(&sheet, (0, 5), 4));
+        assert!(near(&sheet, (1, 5), 4));
+
+        sheet.set_iteration(Iteration::default());
+        sheet.enter((0, 5), "=B6/2+3");
+        assert_eq!(result(&sheet, (0, 5)), Err(ExecutionError::Cyclic));
+    }
+
+    fn formula(sheet: &mut Sheet, pos: Pos, text: &str) -> (Pos, Cell) {
+        let ex = Expression::new(text.to_owned(), pos, &mut sheet.formulas);
+        let cell = Cell::Expression(Rc::new(ex), Err(ExecutionError::NotExecuted));
+        (pos, cell)
+    }
+
+    #[test]
+    fn dependents_are_recomputed_after_what_they_read() {
+        let mut sheet = Sheet::new();
+        sheet.enter((0, 3), "=A2+A3");
+        sheet.enter((0, 2), "=A1*2");
+        sheet.enter((0, 1), "=A1+1");
+        sheet.enter((0, 0), "1");
+        assert_eq!(sheet.shown((0, 3)), "4");
+        sheet.enter((0, 0), "5");
+        assert_eq!(sheet.shown((0, 1)), "6");
+        assert_eq!(sheet.shown((0, 3)), "16");
+        // Through ranges as well
+        sheet.enter((1, 0), "=SUM(A1:A4)");
+        sheet.enter((0, 0), "0");
+        assert_eq!(sheet.shown((1, 0)), "2");
+    }
+
+    #[test]
+    fn batches_are_recomputed_once_in_order() {
+        let mut sheet = Sheet::new();
+        let cells = vec![
+            formula(&mut sheet, (0, 1), "=A1*2"),
+            formula(&mut sheet, (0, 2), "=A2+RAND()*0"),
+            ((0, 0), Cell::Val(Number::Int(3))),
+        ];
+        let generation = sheet.generation();
+        sheet.insert_cells(cells);
+        assert_eq!(sheet.generation(), generation + 1);
+        assert_eq!(sheet.shown((0, 1)), "6");
+        assert_eq!(sheet.shown((0, 2)), "6");
+
+        // Trying out cells leaves volatile functions alone
+        sheet.try_cells(vec![((0, 0), Cell::Val(Number::Int(4)))]);
+        assert_eq!(sheet.generation(), generation + 1);
+        assert_eq!(sheet.shown((0, 2)), "8");
+    }
+
+    #[test]
+    fn cells_are_stored_sparsely() {
+        let mut sheet = Sheet::new();
+        let corner = (COLUMNS - 1, ROWS - 1);
+        sheet.enter(corner, "1");
+        sheet.enter((0, 0), "=XFD1048576+1");
+        assert_eq!(sheet.shown((0, 0)), "2");
+        assert!(sheet.get((COLUMNS, 0)).is_none());
+        assert!(sheet.get((0, ROWS)).is_none());
+        assert!(matches!(sheet.get((5, 5)), Some(Cell::None)));
+        assert_eq!(sheet.cells().count(), 2);
+
+        // Emptying a cell frees it, formulas reading it find nothing
+        sheet.enter(corner, "");
+        assert_eq!(sheet.cells().count(), 1);
+        assert_eq!(result(&sheet, (0, 0)), Err(ExecutionError::CellNotFound));
+    }
+
+    #[test]
+    fn cells_in_a_range_row_by_row() {
+        let mut sheet = Sheet::new();
+        for (x, y) in [(2, 1), (0, 0), (1, 1), (5, 1), (1, 3), (1, 9)] {
+            sheet.enter((x, y), "1");
+        }
+        let found: Vec<Pos> = sheet
+            .cells_in(Range::new((1, 1), (2, 3)))
+            .map(|(p, _)| p)
+            .collect();
+        assert_eq!(found, [(1, 1), (2, 1), (1, 3)]);
     }
 }
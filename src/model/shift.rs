@@ -0,0 +1,359 @@
+//! Moving cells around the sheet, by inserting and deleting whole rows and
+//! columns or by moving a block of cells, while formulas keep pointing at the
+//! same cells
+
+use super::{
+    calc::Expr,
+    reference::{CellRef, Coord, Range},
+};
+
+/// Where cells end up after they are moved around
+pub trait Relocation {
+    /// Where the cell at `pos` ends up, `None` if it is gone
+    fn pos(&self, pos: (usize, usize)) -> Option<(usize, usize)>;
+
+    /// Where the cells of `range` end up as a range, `None` if they are gone
+    fn range(&self, range: Range) -> Option<Range>;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Axis {
+    Column,
+    Row,
+}
+
+/// Rows or columns put into or taken out of the sheet, moving the ones after
+/// them along
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Shift {
+    pub axis: Axis,
+    /// The first row or column inserted or deleted
+    pub at: usize,
+    pub count: usize,
+    pub delete: bool,
+}
+
+impl Shift {
+    pub fn insert(axis: Axis, at: usize, count: usize) -> Self {
+        Shift {
+            axis,
+            at,
+            count,
+            delete: false,
+        }
+    }
+
+    pub fn delete(axis: Axis, at: usize, count: usize) -> Self {
+        Shift {
+            axis,
+            at,
+            count,
+            delete: true,
+        }
+    }
+
+    /// The shift undoing this one, apart from the contents of deleted cells
+    pub fn inverse(&self) -> Self {
+        Shift {
+            delete: !self.delete,
+            ..*self
+        }
+    }
+
+    /// Where the row or column `i` ends up, `None` if it is deleted or
+    /// pushed past any index
+    pub fn index(&self, i: usize) -> Option<usize> {
+        let end = self.at.checked_add(self.count);
+        match self.delete {
+            false if i >= self.at => i.checked_add(self.count),
+            true if end.is_some_and(|end| i >= end) => Some(i - self.count),
+            true if i >= self.at => None,
+            _ => Some(i),
+        }
+    }
+}
+
+impl Relocation for Shift {
+    fn pos(&self, pos: (usize, usize)) -> Option<(usize, usize)> {
+        match self.axis {
+            Axis::Column => Some((self.index(pos.0)?, pos.1)),
+            Axis::Row => Some((pos.0, self.index(pos.1)?)),
+        }
+    }
+
+    /// A range grows when rows or columns are inserted inside of it and
+    /// shrinks when some of its own are deleted
+    fn range(&self, range: Range) -> Option<Range> {
+        let (low, high) = match self.axis {
+            Axis::Column => (range.from.0, range.to.0),
+            Axis::Row => (range.from.1, range.to.1),
+        };
+        let (low, high) = match self.delete {
+            false => (self.index(low)?, self.index(high)?),
+            true => {
+                let end = self.at.saturating_add(self.count);
+                let low = if low < self.at {
+                    low
+                } else {
+                    low.saturating_sub(self.count).max(self.at)
+                };
+                let high = match high {
+                    h if h >= end => h - self.count,
+                    h if h < self.at => h,
+                    _ => self.at.checked_sub(1)?,
+                };
+                (low <= high).then_some((low, high))?
+            }
+        };
+        Some(match self.axis {
+            Axis::Column => Range::new((low, range.from.1), (high, range.to.1)),
+            Axis::Row => Range::new((range.from.0, low), (range.to.0, high)),
+        })
+    }
+}
+
+/// A block of cells cut and pasted elsewhere, replacing the cells there
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Move {
+    pub source: Range,
+    /// The new top left corner
+    pub to: (usize, usize),
+}
+
+impl Move {
+    /// Where the block ends up
+    pub fn target(&self) -> Range {
+        let Range { from, to } = self.source;
+        Range::new(
+            self.to,
+            (self.to.0 + to.0 - from.0, self.to.1 + to.1 - from.1),
+        )
+    }
+}
+
+impl Relocation for Move {
+    /// Cells overwritten by the block are gone
+    fn pos(&self, pos: (usize, usize)) -> Option<(usize, usize)> {
+        let from = self.source.from;
+        if self.source.contains(pos) {
+            Some((pos.0 - from.0 + self.to.0, pos.1 - from.1 + self.to.1))
+        } else if self.target().contains(pos) {
+            None
+        } else {
+            Some(pos)
+        }
+    }
+
+    /// Ranges inside the block move with it, ranges it overwrites as a
+    /// whole are gone like single cells and others stay
+    fn range(&self, range: Range) -> Option<Range> {
+        let target = self.target();
+        if self.source.contains(range.from) && self.source.contains(range.to) {
+            Some(Range::new(self.pos(range.from)?, self.pos(range.to)?))
+        } else if target.contains(range.from) && target.contains(range.to) {
+            None
+        } else {
+            Some(range)
+        }
+    }
+}
+
+/// The rows of a range put in a new order, as when sorting it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Reorder {
+    pub range: Range,
+    /// The row each row of the range ends up in, by their offsets from its
+    /// first row
+    pub rows: Vec<usize>,
+}
+
+impl Relocation for Reorder {
+    fn pos(&self, pos: (usize, usize)) -> Option<(usize, usize)> {
+        match self.range.contains(pos) {
+            true => Some((
+                pos.0,
+                self.range.from.1 + self.rows[pos.1 - self.range.from.1],
+            )),
+            false => Some(pos),
+        }
+    }
+
+    /// Ranges within a row of the range move with it, others stay
+    fn range(&self, range: Range) -> Option<Range> {
+        match range.from.1 == range.to.1
+            && self.range.contains(range.from)
+            && self.range.contains(range.to)
+        {
+            true => Some(Range::new(self.pos(range.from)?, self.pos(range.to)?)),
+            false => Some(range),
+        }
+    }
+}
+
+/// Swaps the column and row offsets of the references of a formula, for
+/// pasting it with its rows turned into columns. References with an absolute
+/// column or row stay as they are.
+pub fn transpose(e: Expr) -> Expr {
+    let swap = |r: CellRef| match (r.x, r.y) {
+        (Coord::Relative(x), Coord::Relative(y)) => CellRef {
+            x: Coord::Relative(y),
+            y: Coord::Relative(x),
+        },
+        _ => r,
+    };
+    match e {
+        Expr::Cell(r) => Expr::Cell(swap(r)),
+        Expr::Range(a, b) => Expr::Range(swap(a), swap(b)),
+        Expr::Op(op, lhs, rhs) => {
+            Expr::Op(op, Box::new(transpose(*lhs)), Box::new(transpose(*rhs)))
+        }
+        Expr::Neg(e) => Expr::Neg(Box::new(transpose(*e))),
+        Expr::Percent(e) => Expr::Percent(Box::new(transpose(*e))),
+        Expr::Call(f, args) => Expr::Call(f, args.into_iter().map(transpose).collect()),
+        Expr::Sum(terms) => Expr::Sum(terms.into_iter().map(transpose).collect()),
+        e @ Expr::Val(_) => e,
+    }
+}
+
+/// Rewrites the references of a formula moving from the cell `from` to `to`
+/// so they keep pointing at the same cells after they are moved. References
+/// to cells that are gone become invalid.
+pub fn rewrite(e: Expr, from: (usize, usize), to: (usize, usize), shift: &impl Relocation) -> Expr {
+    let go = |e: Expr| rewrite(e, from, to, shift);
+    match e {
+        Expr::Cell(r) => Expr::Cell(
+            r.resolve(from)
+                .and_then(|p| shift.pos(p))
+                .map_or(CellRef::INVALID, |p| r.pointed_at(p, to)),
+        ),
+        Expr::Range(a, b) => {
+            let corners = a.resolve(from).zip(b.resolve(from));
+            let Some((pa, pb)) = corners else {
+                return Expr::Cell(CellRef::INVALID);
+            };
+            let before = Range::new(pa, pb);
+            let Some(after) = shift.range(before) else {
+                return Expr::Cell(CellRef::INVALID);
+            };
+            // Each corner keeps its side of the range
+            let corner = |p: (usize, usize)| {
+                (
+                    if p.0 == before.from.0 {
+                        after.from.0
+                    } else {
+                        after.to.0
+                    },
+                    if p.1 == before.from.1 {
+                        after.from.1
+                    } else {
+                        after.to.1
+                    },
+                )
+            };
+            Expr::Range(a.pointed_at(corner(pa), to), b.pointed_at(corner(pb), to))
+        }
+        Expr::Op(op, lhs, rhs) => Expr::Op(op, Box::new(go(*lhs)), Box::new(go(*rhs))),
+        Expr::Neg(e) => Expr::Neg(Box::new(go(*e))),
+        Expr::Percent(e) => Expr::Percent(Box::new(go(*e))),
+        Expr::Call(f, args) => Expr::Call(f, args.into_iter().map(go).collect()),
+        Expr::Sum(terms) => Expr::Sum(terms.into_iter().map(go).collect()),
+        e @ Expr::Val(_) => e,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::model::sheet::{Sheet, ROWS};
+
+    use super::*;
+
+    fn sheet() -> Sheet {
+        let mut sheet = Sheet::new();
+        sheet.enter((0, 0), "1");
+        sheet.enter((0, 1), "2");
+        sheet.enter((0, 2), "3");
+        sheet.enter((2, 4), "=A1+SUM(A1:A3)");
+        sheet
+    }
+
+    #[test]
+    fn indexes_after_a_shift() {
+        let insert = Shift::insert(Axis::Row, 2, 3);
+        assert_eq!(insert.index(1), Some(1));
+        assert_eq!(insert.index(2), Some(5));
+        let delete = insert.inverse();
+        assert_eq!(delete.index(3), None);
+        assert_eq!(delete.index(5), Some(2));
+        // Counts past any index do not wrap around
+        assert_eq!(Shift::insert(Axis::Row, 2, usize::MAX).index(3), None);
+        assert_eq!(Shift::delete(Axis::Row, 2, usize::MAX).index(3), None);
+        assert_eq!(Shift::delete(Axis::Row, 2, usize::MAX).index(1), Some(1));
+    }
+
+    #[test]
+    fn inserting_grows_ranges() {
+        let mut sheet = sheet();
+        assert!(sheet.shift(Shift::insert(Axis::Row, 1, 2)));
+        assert_eq!(sheet.entry((2, 6)), "=A1+SUM(A1:A5)");
+        assert_eq!(sheet.shown((2, 6)), "7");
+        assert!(sheet.shift(Shift::insert(Axis::Column, 0, 1)));
+        assert_eq!(sheet.entry((3, 6)), "=B1+SUM(B1:B5)");
+    }
+
+    #[test]
+    fn deleting_shrinks_ranges_and_invalidates_cells() {
+        let mut sheet = sheet();
+        assert!(sheet.shift(Shift::delete(Axis::Row, 1, 1)));
+        assert_eq!(sheet.entry((2, 3)), "=A1+SUM(A1:A2)");
+        assert_eq!(sheet.shown((2, 3)), "5");
+        assert!(sheet.shift(Shift::delete(Axis::Row, 0, 1)));
+        assert_eq!(sheet.entry((2, 2)), "=#REF!+SUM(A1:A1)");
+        assert_eq!(sheet.shown((2, 2)), "#Error");
+        assert!(sheet.shift(Shift::delete(Axis::Row, 0, 1)));
+        assert_eq!(sheet.entry((2, 1)), "=#REF!+SUM(#REF!)");
+    }
+
+    #[test]
+    fn shifts_pushing_cells_off_the_sheet_fail() {
+        let mut sheet = sheet();
+        sheet.enter((0, ROWS - 1), "1");
+        assert!(!sheet.shift(Shift::insert(Axis::Row, 0, 1)));
+        assert!(!sheet.shift(Shift::insert(Axis::Row, 0, usize::MAX)));
+        assert_eq!(sheet.entry((2, 4)), "=A1+SUM(A1:A3)");
+        // Rows past all cells may come and go
+        assert!(sheet.shift(Shift::insert(Axis::Row, ROWS, 1)));
+        assert!(sheet.shift(Shift::delete(Axis::Row, 10, usize::MAX)));
+        assert_eq!(sheet.cells().count(), 4);
+    }
+
+    #[test]
+    fn moving_a_block_keeps_references() {
+        let mut sheet = sheet();
+        sheet.enter((4, 0), "=A2");
+        sheet.enter((0, 10), "=SUM(A1:A2)");
+        let mv = Move {
+            source: Range::new((0, 0), (0, 1)),
+            to: (1, 5),
+        };
+        assert!(sheet.move_cells(mv));
+        assert_eq!(sheet.entry((4, 0)), "=B7");
+        assert_eq!(sheet.entry((0, 10)), "=SUM(B6:B7)");
+        // Partly moved ranges stay
+        assert_eq!(sheet.entry((2, 4)), "=B6+SUM(A1:A3)");
+        assert_eq!(sheet.shown((0, 10)), "3");
+    }
+
+    #[test]
+    fn ranges_overwritten_by_a_block_are_gone() {
+        let mut sheet = sheet();
+        sheet.enter((5, 0), "=SUM(B6:B7)");
+        sheet.enter((5, 1), "=SUM(B6:B8)");
+        let mv = Move {
+            source: Range::new((0, 0), (0, 1)),
+            to: (1, 5),
+        };
+        assert!(sheet.move_cells(mv));
+        assert_eq!(sheet.entry((5, 0)), "=SUM(#REF!)");
+        assert_eq!(sheet.entry((5, 1)), "=SUM(B6:B8)");
+    }
+}
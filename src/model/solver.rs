@@ -0,0 +1,378 @@
+//! Finding the inputs that make a formula take a wanted value
+
+use num_traits::{FromPrimitive, ToPrimitive};
+use rust_decimal::Decimal;
+
+use super::{
+    number::Number,
+    sheet::{Cell, Sheet},
+};
+
+/// Most formula evaluations goal seek makes before giving up
+const MAX_STEPS: usize = 100;
+/// Most formula evaluations the solver makes before settling for the best
+const MAX_EVALUATIONS: usize = 5000;
+/// Inputs are stored with this many significant digits
+const SIGNIFICANT_DIGITS: u32 = 15;
+/// A target counts as hit when off by at most this, relative to the target
+const TOLERANCE: f64 = 1e-9;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SolveError {
+    /// An input cell holds a formula or text instead of a number
+    InvalidInput,
+    /// The formula did not give a number for any input tried
+    NotANumber,
+    /// The target could not be reached within the number of steps allowed
+    NoConvergence,
+}
+
+/// What the solver aims for with the objective formula
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Objective {
+    Min,
+    Max,
+    Target(f64),
+}
+
+impl Objective {
+    /// Lower is better
+    fn score(&self, value: f64) -> f64 {
+        match self {
+            Objective::Min => value,
+            Objective::Max => -value,
+            Objective::Target(t) => (value - t).abs(),
+        }
+    }
+}
+
+/// An input cell the solver may change, kept between `low` and `high`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Variable {
+    pub pos: (usize, usize),
+    pub low: f64,
+    pub high: f64,
+}
+
+/// Changes the number in `input` until the `formula` cell gives `target`,
+/// by the secant method and bisection once the target is bracketed. Leaves
+/// the input as found and returns it, or restores it on failure.
+pub fn goal_seek(
+    sheet: &mut Sheet,
+    formula: (usize, usize),
+    target: f64,
+    input: (usize, usize),
+) -> Result<f64, SolveError> {
+    let original = input_cell(sheet, input)?;
+    let start = original.val().and_then(|n| to_f64(&n)).unwrap_or(0.0);
+
+    let res = seek(sheet, formula, target, input, start);
+    if res.is_err() {
+        restore(sheet, &[(input, original)]);
+    }
+    res
+}
+
+fn seek(
+    sheet: &mut Sheet,
+    formula: (usize, usize),
+    target: f64,
+    input: (usize, usize),
+    start: f64,
+) -> Result<f64, SolveError> {
+    let tolerance = TOLERANCE * target.abs().max(1.0);
+    let mut f = |x: f64| probe(sheet, &[(input, x)], formula).map(|v| v - target);
+
+    let (mut x0, mut x1) = (start, start + (start.abs() * 0.01).max(0.01));
+    let mut f0 = f(x0).ok_or(SolveError::NotANumber)?;
+    if f0.abs() <= tolerance {
+        return Ok(x0);
+    }
+    let mut f1 = f(x1).ok_or(SolveError::NotANumber)?;
+    // Two inputs with results on either side of the target
+    let mut bracket = None;
+
+    for _ in 0..MAX_STEPS {
+        if f1.abs() <= tolerance {
+            return Ok(x1);
+        }
+        if bracket.is_none() && f0.signum() != f1.signum() {
+            bracket = Some((x0, f0, x1, f1));
+        }
+
+        let mut x2 = x1 - f1 * (x1 - x0) / (f1 - f0);
+        if let Some((a, _, b, _)) = bracket {
+            if !(x2 > a.min(b) && x2 < a.max(b)) {
+                x2 = (a + b) / 2.0;
+            }
+        }
+        if !x2.is_finite() {
+            break;
+        }
+        let f2 = f(x2).ok_or(SolveError::NotANumber)?;
+
+        if let Some((a, fa, b, fb)) = bracket {
+            bracket = match f2.signum() == fa.signum() {
+                true => Some((x2, f2, b, fb)),
+                false => Some((a, fa, x2, f2)),
+            };
+        }
+        (x0, f0, x1, f1) = (x1, f1, x2, f2);
+    }
+
+    Err(SolveError::NoConvergence)
+}
+
+/// Changes the numbers in `variables` within their bounds to minimise,
+/// maximise or hit a target with the `objective` cell, searching along one
+/// variable at a time with shrinking steps. Leaves the best inputs found and
+/// returns them, or restores the inputs on failure.
+pub fn solve(
+    sheet: &mut Sheet,
+    objective_pos: (usize, usize),
+    objective: Objective,
+    variables: &[Variable],
+) -> Result<Vec<f64>, SolveError> {
+    let originals = variables
+        .iter()
+        .map(|v| Ok((v.pos, input_cell(sheet, v.pos)?)))
+        .collect::<Result<Vec<_>, SolveError>>()?;
+
+    let mut x: Vec<f64> = originals
+        .iter()
+        .zip(variables)
+        .map(|((_, c), v)| {
+            c.val()
+                .and_then(|n| to_f64(&n))
+                .unwrap_or(0.0)
+                .clamp(v.low, v.high)
+        })
+        .collect();
+    let mut steps: Vec<f64> = x
+        .iter()
+        .zip(variables)
+        .map(|(x, v)| match (v.high - v.low).is_finite() {
+            true => (v.high - v.low) / 4.0,
+            false => (x.abs() / 2.0).max(1.0),
+        })
+        .collect();
+
+    let mut score = |x: &[f64]| {
+        let inputs: Vec<_> = variables
+            .iter()
+            .map(|v| v.pos)
+            .zip(x.iter().copied())
+            .collect();
+        probe(sheet, &inputs, objective_pos).map_or(f64::INFINITY, |v| objective.score(v))
+    };
+    let mut best = score(&x);
+    let mut evaluations = 1;
+    let reached = |best: f64| match objective {
+        Objective::Target(t) => best <= TOLERANCE * t.abs().max(1.0),
+        _ => false,
+    };
+
+    while evaluations < MAX_EVALUATIONS && !reached(best) {
+        let mut improved = false;
+        for i in 0..x.len() {
+            for direction in [1.0, -1.0] {
+                let mut candidate = x.clone();
+                candidate[i] =
+                    (x[i] + direction * steps[i]).clamp(variables[i].low, variables[i].high);
+                if candidate[i] == x[i] {
+                    continue;
+                }
+                let s = score(&candidate);
+                evaluations += 1;
+                if s < best {
+                    (x, best, improved) = (candidate, s, true);
+                    break;
+                }
+            }
+        }
+
+        if !improved {
+            steps.iter_mut().for_each(|s| *s /= 2.0);
+            if steps
+                .iter()
+                .zip(&x)
+                .all(|(s, x)| *s <= TOLERANCE * x.abs().max(1.0))
+            {
+                break;
+            }
+        }
+    }
+
+    let inputs: Vec<_> = variables
+        .iter()
+        .map(|v| v.pos)
+        .zip(x.iter().copied())
+        .collect();
+    let failed = match objective {
+        _ if !best.is_finite() => Some(SolveError::NotANumber),
+        Objective::Target(_) if !reached(best) => Some(SolveError::NoConvergence),
+        _ => None,
+    };
+    match failed {
+        Some(e) => {
+            restore(sheet, &originals);
+            Err(e)
+        }
+        None => {
+            probe(sheet, &inputs, objective_pos);
+            Ok(x)
+        }
+    }
+}
+
+/// The cell at `pos` if it may be changed by the solver
+fn input_cell(sheet: &Sheet, pos: (usize, usize)) -> Result<Cell, SolveError> {
+    match sheet.get(pos) {
+        Some(c @ (Cell::None | Cell::Val(_))) => Ok(c.clone()),
+        _ => Err(SolveError::InvalidInput),
+    }
+}
+
+/// Puts the numbers into their cells and gives the resulting value of
+/// `formula`. Volatile functions keep their values between probes.
+fn probe(
+    sheet: &mut Sheet,
+    inputs: &[((usize, usize), f64)],
+    formula: (usize, usize),
+) -> Option<f64> {
+    let cells = inputs
+        .iter()
+        .map(|&(pos, x)| {
+            let d = Decimal::from_f64(x)?
+                .round_sf(SIGNIFICANT_DIGITS)?
+                .normalize();
+            Some((pos, Cell::Val(Number::Decimal(d))))
+        })
+        .collect::<Option<Vec<_>>>()?;
+    sheet.try_cells(cells);
+    to_f64(&sheet.get(formula)?.val()?)
+}
+
+fn restore(sheet: &mut Sheet, cells: &[((usize, usize), Cell)]) {
+    sheet.insert_cells(cells.to_vec());
+}
+
+fn to_f64(n: &Number) -> Option<f64> {
+    n.to_decimal().ok()?.to_f64()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn value(sheet: &Sheet, pos: (usize, usize)) -> f64 {
+        to_f64(&sheet.get(pos).unwrap().val().unwrap()).unwrap()
+    }
+
+    fn close(a: f64, b: f64) -> bool {
+        (a - b).abs() <= 1e-6
+    }
+
+    #[test]
+    fn goal_seek_finds_the_input() {
+        let mut sheet = Sheet::new();
+        sheet.enter((0, 0), "1");
+        sheet.enter((1, 0), "=A1*3+1");
+        let x = goal_seek(&mut sheet, (1, 0), 10.0, (0, 0)).unwrap();
+        assert!(close(x, 3.0));
+        assert!(close(value(&sheet, (1, 0)), 10.0));
+
+        sheet.enter((1, 0), "=A1*A1");
+        let x = goal_seek(&mut sheet, (1, 0), 2.0, (0, 0)).unwrap();
+        assert!(close(x.abs(), 2f64.sqrt()));
+    }
+
+    #[test]
+    fn goal_seek_failing_restores_the_input() {
+        let mut sheet = Sheet::new();
+        sheet.enter((0, 0), "5");
+        sheet.enter((1, 0), "=A1*A1");
+        assert_eq!(
+            goal_seek(&mut sheet, (1, 0), -1.0, (0, 0)),
+            Err(SolveError::NoConvergence)
+        );
+        assert_eq!(sheet.shown((0, 0)), "5");
+        assert_eq!(sheet.shown((1, 0)), "25");
+
+        sheet.enter((2, 0), "=A1");
+        assert_eq!(
+            goal_seek(&mut sheet, (1, 0), 1.0, (2, 0)),
+            Err(SolveError::InvalidInput)
+        );
+        sheet.enter((2, 0), "some text");
+        assert_eq!(
+            goal_seek(&mut sheet, (1, 0), 1.0, (2, 0)),
+            Err(SolveError::InvalidInput)
+        );
+    }
+
+    #[test]
+    fn volatile_functions_hold_still_while_seeking() {
+        let mut sheet = Sheet::new();
+        sheet.enter((0, 0), "0");
+        sheet.enter((1, 0), "=A1*2+RAND()");
+        assert!(goal_seek(&mut sheet, (1, 0), 10.0, (0, 0)).is_ok());
+        assert!(close(value(&sheet, (1, 0)), 10.0));
+    }
+
+    #[test]
+    fn solver_minimises_and_maximises_within_bounds() {
+        let mut sheet = Sheet::new();
+        sheet.enter((2, 0), "=(A1-3)*(A1-3)+(B1+1)*(B1+1)");
+        let bounded = |x| Variable {
+            pos: (x, 0),
+            low: -10.0,
+            high: 10.0,
+        };
+        let x = solve(
+            &mut sheet,
+            (2, 0),
+            Objective::Min,
+            &[bounded(0), bounded(1)],
+        )
+        .unwrap();
+        assert!(close(x[0], 3.0) && close(x[1], -1.0), "{:?}", x);
+
+        sheet.enter((2, 0), "=A1+B1");
+        let x = solve(
+            &mut sheet,
+            (2, 0),
+            Objective::Max,
+            &[bounded(0), bounded(1)],
+        )
+        .unwrap();
+        assert_eq!(x, vec![10.0, 10.0]);
+        assert_eq!(sheet.shown((2, 0)), "20");
+
+        let x = solve(
+            &mut sheet,
+            (2, 0),
+            Objective::Target(5.0),
+            &[bounded(0), bounded(1)],
+        )
+        .unwrap();
+        assert!(close(x[0] + x[1], 5.0));
+    }
+
+    #[test]
+    fn solver_failing_restores_the_inputs() {
+        let mut sheet = Sheet::new();
+        sheet.enter((0, 0), "1");
+        sheet.enter((1, 0), "=A1*A1");
+        let variable = Variable {
+            pos: (0, 0),
+            low: 1.0,
+            high: 2.0,
+        };
+        assert_eq!(
+            solve(&mut sheet, (1, 0), Objective::Target(9.0), &[variable]),
+            Err(SolveError::NoConvergence)
+        );
+        assert_eq!(sheet.shown((0, 0)), "1");
+    }
+}
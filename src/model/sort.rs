@@ -0,0 +1,216 @@
+//! Sorting the rows of a range by the values in some of its columns
+
+use std::cmp::Ordering;
+
+use super::{
+    reference::Range,
+    sheet::{Cell, Sheet},
+    shift::Reorder,
+    value::Value,
+};
+
+/// A column to sort by, the first key decides unless its values are equal
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SortKey {
+    pub column: usize,
+    pub descending: bool,
+}
+
+/// What a row is sorted by in one column. Errors and then empty cells come
+/// last in either direction.
+#[derive(Debug, Clone, PartialEq)]
+enum Key {
+    Value(Value),
+    Error,
+    Empty,
+}
+
+/// Sorts the rows of `range` by `keys`, rows with equal keys keep their
+/// order. The first row stays in place if it is a header, without `header`
+/// it is taken as one if it holds text above a column with numbers. Fails if
+/// a key is outside of the range.
+pub fn sort(sheet: &mut Sheet, range: Range, keys: &[SortKey], header: Option<bool>) -> bool {
+    if keys.is_empty()
+        || keys
+            .iter()
+            .any(|k| !(range.from.0..=range.to.0).contains(&k.column))
+    {
+        return false;
+    }
+    let header = header.unwrap_or_else(|| has_header(sheet, range, keys));
+    if header && range.height() == 1 {
+        return true;
+    }
+    let rows = Range::new((range.from.0, range.from.1 + header as usize), range.to);
+
+    let keyed: Vec<Vec<Key>> = (rows.from.1..=rows.to.1)
+        .map(|y| keys.iter().map(|k| key(sheet.get((k.column, y)))).collect())
+        .collect();
+    let mut order: Vec<usize> = (0..keyed.len()).collect();
+    order.sort_by(|&a, &b| {
+        keys.iter()
+            .zip(keyed[a].iter().zip(&keyed[b]))
+            .map(|(k, (a, b))| compare(a, b, k.descending))
+            .find(|o| o.is_ne())
+            .unwrap_or(Ordering::Equal)
+    });
+
+    // The new row of every row instead of the old row of every new one
+    let mut moved = vec![0; order.len()];
+    for (to, from) in order.into_iter().enumerate() {
+        moved[from] = to;
+    }
+    if moved.iter().enumerate().all(|(from, to)| from == *to) {
+        return true;
+    }
+    sheet.reorder(&Reorder {
+        range: rows,
+        rows: moved,
+    });
+    true
+}
+
+fn key(cell: Option<&Cell>) -> Key {
+    match cell.and_then(Cell::result) {
+        Some(Ok(v)) => Key::Value(v),
+        Some(Err(_)) => Key::Error,
+        None => Key::Empty,
+    }
+}
+
+fn compare(a: &Key, b: &Key, descending: bool) -> Ordering {
+    match (a, b) {
+        (Key::Value(a), Key::Value(b)) => {
+            let order = match (a, b) {
+                (Value::Text(a), Value::Text(b)) => natural(a, b),
+                (a, b) => a.compare(b),
+            };
+            match descending {
+                true => order.reverse(),
+                false => order,
+            }
+        }
+        (Key::Value(_), _) => Ordering::Less,
+        (_, Key::Value(_)) => Ordering::Greater,
+        (Key::Error, Key::Empty) => Ordering::Less,
+        (Key::Empty, Key::Error) => Ordering::Greater,
+        _ => Ordering::Equal,
+    }
+}
+
+/// Compares text without regard to case, with the numbers in it compared by
+/// their value so `item 9` comes before `item 10`
+fn natural(a: &str, b: &str) -> Ordering {
+    let (mut a, mut b) = (a.chars().peekable(), b.chars().peekable());
+    loop {
+        match (a.peek().copied(), b.peek().copied()) {
+            (None, None) => return Ordering::Equal,
+            (None, Some(_)) => return Ordering::Less,
+            (Some(_), None) => return Ordering::Greater,
+            (Some(x), Some(y)) if x.is_ascii_digit() && y.is_ascii_digit() => {
+                let digits = |chars: &mut std::iter::Peekable<std::str::Chars>| {
+                    let mut run = String::new();
+                    while let Some(c) = chars.next_if(char::is_ascii_digit) {
+                        run.push(c);
+                    }
+                    run
+                };
+                let (x, y) = (digits(&mut a), digits(&mut b));
+                let (tx, ty) = (x.trim_start_matches('0'), y.trim_start_matches('0'));
+                // Equal numbers with more leading zeros come last
+                let order = tx
+                    .len()
+                    .cmp(&ty.len())
+                    .then_with(|| tx.cmp(ty))
+                    .then_with(|| x.len().cmp(&y.len()));
+                if order.is_ne() {
+                    return order;
+                }
+            }
+            (Some(x), Some(y)) => {
+                let order = x.to_lowercase().cmp(y.to_lowercase());
+                if order.is_ne() {
+                    return order;
+                }
+                a.next();
+                b.next();
+            }
+        }
+    }
+}
+
+/// Whether the first row of `range` holds only text in the key columns
+/// while some of them hold numbers below it
+fn has_header(sheet: &Sheet, range: Range, keys: &[SortKey]) -> bool {
+    let text = |c: Option<&Cell>| matches!(c.and_then(Cell::result), Some(Ok(Value::Text(_))));
+    keys.iter()
+        .all(|k| text(sheet.get((k.column, range.from.1))))
+        && keys.iter().any(|k| {
+            (range.from.1 + 1..=range.to.1)
+                .any(|y| matches!(key(sheet.get((k.column, y))), Key::Value(Value::Number(_))))
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn column(sheet: &Sheet, x: usize, rows: std::ops::Range<usize>) -> Vec<String> {
+        rows.map(|y| sheet.entry((x, y))).collect()
+    }
+
+    fn by(column: usize, descending: bool) -> SortKey {
+        SortKey { column, descending }
+    }
+
+    #[test]
+    fn natural_order() {
+        let mut names = vec![
+            "item 10", "Item 9", "item 09", "item", "apple 2b", "apple 2a",
+        ];
+        names.sort_by(|a, b| natural(a, b));
+        assert_eq!(
+            names,
+            ["apple 2a", "apple 2b", "item", "Item 9", "item 09", "item 10"]
+        );
+        assert_eq!(natural("A", "a"), Ordering::Equal);
+        assert_eq!(
+            natural("x99999999999999999999999", "x100000000000000000000000"),
+            Ordering::Less
+        );
+    }
+
+    #[test]
+    fn errors_and_empty_cells_come_last() {
+        let mut sheet = Sheet::new();
+        for (y, s) in ["b", "", "=1/0", "3", "a", "1"].iter().enumerate() {
+            sheet.enter((0, y), s);
+        }
+        let range = Range::new((0, 0), (0, 5));
+        assert!(sort(&mut sheet, range, &[by(0, false)], Some(false)));
+        assert_eq!(column(&sheet, 0, 0..6), ["1", "3", "a", "b", "=1/0", ""]);
+        assert!(sort(&mut sheet, range, &[by(0, true)], Some(false)));
+        assert_eq!(column(&sheet, 0, 0..6), ["b", "a", "3", "1", "=1/0", ""]);
+    }
+
+    #[test]
+    fn sorting_by_several_keys_keeps_rows_together() {
+        let mut sheet = Sheet::new();
+        let rows = [("Name", "Age"), ("bo", "30"), ("al", "30"), ("cy", "20")];
+        for (y, (name, age)) in rows.iter().enumerate() {
+            sheet.enter((0, y), name);
+            sheet.enter((1, y), age);
+        }
+        sheet.enter((2, 1), "=B2*2");
+        let range = Range::new((0, 0), (2, 3));
+        // The header is found from the text above the numbers
+        assert!(sort(&mut sheet, range, &[by(1, true), by(0, false)], None));
+        assert_eq!(column(&sheet, 0, 0..4), ["Name", "al", "bo", "cy"]);
+        assert_eq!(column(&sheet, 1, 0..4), ["Age", "30", "30", "20"]);
+        assert_eq!(sheet.entry((2, 2)), "=B3*2");
+        assert_eq!(sheet.shown((2, 2)), "60");
+
+        assert!(!sort(&mut sheet, range, &[by(3, false)], None));
+        assert!(!sort(&mut sheet, range, &[], None));
+    }
+}
@@ -0,0 +1,68 @@
+use std::{cmp::Ordering, fmt::Display};
+
+use super::{calc::ExecutionError, number::Number};
+
+/// The result of evaluating a formula
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Number(Number),
+    Text(String),
+    Bool(bool),
+}
+
+impl Value {
+    /// The value as a number, booleans count as 1 and 0
+    pub fn number(&self) -> Result<Number, ExecutionError> {
+        match self {
+            Value::Number(n) => Ok(n.clone()),
+            Value::Bool(b) => Ok(Number::Int(*b as i64)),
+            Value::Text(_) => Err(ExecutionError::WrongType),
+        }
+    }
+
+    /// The value as a condition, numbers are true unless they are zero
+    pub fn truthy(&self) -> Result<bool, ExecutionError> {
+        match self {
+            Value::Bool(b) => Ok(*b),
+            Value::Number(n) => Ok(!n.is_zero()),
+            Value::Text(_) => Err(ExecutionError::WrongType),
+        }
+    }
+
+    /// Orders values like a spreadsheet, numbers before text before booleans
+    /// and text without regard to case
+    pub fn compare(&self, other: &Value) -> Ordering {
+        match (self, other) {
+            (Value::Number(a), Value::Number(b)) => a.compare(b),
+            (Value::Text(a), Value::Text(b)) => a.to_lowercase().cmp(&b.to_lowercase()),
+            (Value::Bool(a), Value::Bool(b)) => a.cmp(b),
+            _ => self.rank().cmp(&other.rank()),
+        }
+    }
+
+    fn rank(&self) -> u8 {
+        match self {
+            Value::Number(_) => 0,
+            Value::Text(_) => 1,
+            Value::Bool(_) => 2,
+        }
+    }
+
+    pub fn text(&self) -> Result<&str, ExecutionError> {
+        match self {
+            Value::Text(s) => Ok(s),
+            _ => Err(ExecutionError::WrongType),
+        }
+    }
+}
+
+impl Display for Value {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Value::Number(n) => write!(f, "{}", n),
+            Value::Text(s) => write!(f, "{}", s),
+            Value::Bool(true) => write!(f, "TRUE"),
+            Value::Bool(false) => write!(f, "FALSE"),
+        }
+    }
+}
@@ -0,0 +1,380 @@
+//! Comparing the results of formulas under different inputs
+
+use super::{
+    reference::{column_name, Range},
+    sheet::{Cell, Sheet},
+    shift::{Relocation, Shift},
+    value::Value,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum WhatIfError {
+    /// An input cell holds a formula, which would be lost by changing it
+    InvalidInput,
+    /// A data table needs at least one input and room for results
+    InvalidTable,
+    UnknownScenario,
+    /// No scenario is shown, so there is nothing to go back to
+    NothingToRevert,
+}
+
+/// A named set of values for input cells
+#[derive(Debug, Clone)]
+pub struct Scenario {
+    pub name: String,
+    pub values: Vec<((usize, usize), Cell)>,
+}
+
+/// The scenarios of a sheet and the inputs as they were before one was shown
+#[derive(Debug, Clone, Default)]
+pub struct Scenarios {
+    list: Vec<Scenario>,
+    saved: Option<Vec<((usize, usize), Cell)>>,
+}
+
+impl Scenarios {
+    /// Moves the input cells along with `shift`, forgetting deleted ones
+    pub fn shift(&mut self, shift: &Shift) {
+        let moved = |values: &mut Vec<((usize, usize), Cell)>| {
+            *values = std::mem::take(values)
+                .into_iter()
+                .filter_map(|(pos, cell)| Some((shift.pos(pos)?, cell)))
+                .collect();
+        };
+        self.list.iter_mut().for_each(|s| moved(&mut s.values));
+        if let Some(saved) = &mut self.saved {
+            moved(saved);
+        }
+    }
+}
+
+/// Fills `range` with the results of formulas for a series of inputs. With
+/// `column_input` the inputs go down the first column and the formulas are
+/// along the first row, with `row_input` the other way around. With both,
+/// the single formula is in the top left corner, the inputs for `row_input`
+/// along the first row and those for `column_input` down the first column.
+pub fn data_table(
+    sheet: &mut Sheet,
+    range: Range,
+    row_input: Option<(usize, usize)>,
+    column_input: Option<(usize, usize)>,
+) -> Result<(), WhatIfError> {
+    if range.width() < 2 || range.height() < 2 || (row_input.is_none() && column_input.is_none()) {
+        return Err(WhatIfError::InvalidTable);
+    }
+    let inputs: Vec<_> = row_input.into_iter().chain(column_input).collect();
+    let originals = inputs
+        .iter()
+        .map(|pos| Ok((*pos, input_cell(sheet, *pos)?)))
+        .collect::<Result<Vec<_>, WhatIfError>>()?;
+
+    // The inputs are tried out without recalculating, so volatile functions
+    // keep their values across the table
+    let Range {
+        from: (left, top),
+        to: (right, bottom),
+    } = range;
+    let mut results = vec![];
+    for x in left + 1..=right {
+        for y in top + 1..=bottom {
+            let (cells, formula) = match (row_input, column_input) {
+                (Some(row), Some(column)) => (
+                    vec![
+                        (row, value_cell(sheet, (x, top))),
+                        (column, value_cell(sheet, (left, y))),
+                    ],
+                    (left, top),
+                ),
+                (Some(row), None) => (vec![(row, value_cell(sheet, (x, top)))], (left, y)),
+                (None, Some(column)) => (vec![(column, value_cell(sheet, (left, y)))], (x, top)),
+                (None, None) => unreachable!(),
+            };
+            sheet.try_cells(cells);
+            results.push(((x, y), value_cell(sheet, formula)));
+        }
+    }
+
+    let mut cells = originals;
+    cells.extend(results);
+    sheet.insert_cells(cells);
+    Ok(())
+}
+
+/// Adds or replaces the scenario `name`. Cells without a value keep their
+/// current one in the scenario.
+pub fn add_scenario(
+    sheet: &mut Sheet,
+    name: &str,
+    cells: Vec<((usize, usize), Option<Value>)>,
+) -> Result<(), WhatIfError> {
+    let mut values = vec![];
+    for (pos, value) in cells {
+        let current = input_cell(sheet, pos)?;
+        values.push((pos, value.map_or(current, to_cell)));
+    }
+
+    let scenarios = &mut sheet.scenarios.list;
+    scenarios.retain(|s| s.name != name);
+    scenarios.push(Scenario {
+        name: name.to_owned(),
+        values,
+    });
+    Ok(())
+}
+
+pub fn delete_scenario(sheet: &mut Sheet, name: &str) -> Result<(), WhatIfError> {
+    let scenarios = &mut sheet.scenarios.list;
+    let i = scenarios
+        .iter()
+        .position(|s| s.name == name)
+        .ok_or(WhatIfError::UnknownScenario)?;
+    scenarios.remove(i);
+    Ok(())
+}
+
+/// Puts the values of the scenario `name` into their cells, remembering the
+/// values from before any scenario was shown for [revert_scenario]
+pub fn show_scenario(sheet: &mut Sheet, name: &str) -> Result<(), WhatIfError> {
+    let scenario = sheet
+        .scenarios
+        .list
+        .iter()
+        .find(|s| s.name == name)
+        .cloned()
+        .ok_or(WhatIfError::UnknownScenario)?;
+
+    // Every input is checked before any of them changes
+    let current = scenario
+        .values
+        .iter()
+        .map(|(pos, _)| Ok((*pos, input_cell(sheet, *pos)?)))
+        .collect::<Result<Vec<_>, WhatIfError>>()?;
+    let saved = sheet.scenarios.saved.get_or_insert_with(Vec::new);
+    for (pos, cell) in current {
+        if !saved.iter().any(|(p, _)| *p == pos) {
+            saved.push((pos, cell));
+        }
+    }
+    sheet.insert_cells(scenario.values);
+    Ok(())
+}
+
+/// Puts back the values from before the first scenario was shown
+pub fn revert_scenario(sheet: &mut Sheet) -> Result<(), WhatIfError> {
+    let saved = sheet
+        .scenarios
+        .saved
+        .take()
+        .ok_or(WhatIfError::NothingToRevert)?;
+    sheet.insert_cells(saved);
+    Ok(())
+}
+
+/// Writes a table starting at `pos` with a column per scenario, giving its
+/// input values followed by the values of the `results` cells under it
+pub fn scenario_summary(
+    sheet: &mut Sheet,
+    pos: (usize, usize),
+    results: &[(usize, usize)],
+) -> Result<(), WhatIfError> {
+    let scenarios = sheet.scenarios.list.clone();
+    let mut inputs: Vec<(usize, usize)> = vec![];
+    for (p, _) in scenarios.iter().flat_map(|s| &s.values) {
+        if !inputs.contains(p) {
+            inputs.push(*p);
+        }
+    }
+    let originals = inputs
+        .iter()
+        .map(|p| Ok((*p, input_cell(sheet, *p)?)))
+        .collect::<Result<Vec<_>, WhatIfError>>()?;
+
+    let mut columns = vec![];
+    for s in &scenarios {
+        // Inputs the scenario leaves out keep their values from before
+        let mut cells = originals.clone();
+        cells.extend(s.values.iter().cloned());
+        sheet.try_cells(cells);
+        let mut column = vec![Cell::String(s.name.clone())];
+        column.extend(inputs.iter().chain(results).map(|p| value_cell(sheet, *p)));
+        columns.push(column);
+    }
+
+    let mut labels = vec![Cell::None];
+    labels.extend(
+        inputs
+            .iter()
+            .chain(results)
+            .map(|(x, y)| Cell::String(format!("{}{}", column_name(*x), y + 1))),
+    );
+    let mut cells = originals;
+    for (i, column) in std::iter::once(labels).chain(columns).enumerate() {
+        cells.extend(
+            column
+                .into_iter()
+                .enumerate()
+                .map(|(j, cell)| ((pos.0 + i, pos.1 + j), cell)),
+        );
+    }
+    sheet.insert_cells(cells);
+    Ok(())
+}
+
+/// The cell at `pos` if its value may be changed
+fn input_cell(sheet: &Sheet, pos: (usize, usize)) -> Result<Cell, WhatIfError> {
+    match sheet.get(pos) {
+        Some(Cell::Expression(_, _)) | None => Err(WhatIfError::InvalidInput),
+        Some(c) => Ok(c.clone()),
+    }
+}
+
+/// A plain cell holding the current value of the cell at `pos`
+fn value_cell(sheet: &Sheet, pos: (usize, usize)) -> Cell {
+    sheet.get(pos).map_or(Cell::None, Cell::computed)
+}
+
+fn to_cell(v: Value) -> Cell {
+    match v {
+        Value::Number(n) => Cell::Val(n),
+        Value::Text(s) => Cell::String(s),
+        v @ Value::Bool(_) => Cell::String(v.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::model::number::Number;
+
+    use super::*;
+
+    fn number(n: i64) -> Option<Value> {
+        Some(Value::Number(Number::Int(n)))
+    }
+
+    #[test]
+    fn one_input_tables() {
+        let mut sheet = Sheet::new();
+        sheet.enter((0, 0), "1");
+        sheet.enter((3, 1), "2");
+        sheet.enter((3, 2), "3");
+        sheet.enter((4, 0), "=A1*10");
+        sheet.enter((5, 0), "=A1+1");
+        data_table(&mut sheet, Range::new((3, 0), (5, 2)), None, Some((0, 0))).unwrap();
+        assert_eq!(sheet.shown((4, 1)), "20");
+        assert_eq!(sheet.shown((4, 2)), "30");
+        assert_eq!(sheet.shown((5, 2)), "4");
+        // The input and the formulas are left as they were
+        assert_eq!(sheet.shown((0, 0)), "1");
+        assert_eq!(sheet.shown((4, 0)), "10");
+    }
+
+    #[test]
+    fn two_input_tables() {
+        let mut sheet = Sheet::new();
+        sheet.enter((0, 0), "1");
+        sheet.enter((1, 0), "1");
+        sheet.enter((3, 0), "=A1*B1");
+        sheet.enter((4, 0), "2");
+        sheet.enter((5, 0), "3");
+        sheet.enter((3, 1), "10");
+        sheet.enter((3, 2), "20");
+        let table = Range::new((3, 0), (5, 2));
+        data_table(&mut sheet, table, Some((0, 0)), Some((1, 0))).unwrap();
+        assert_eq!(sheet.shown((4, 1)), "20");
+        assert_eq!(sheet.shown((5, 2)), "60");
+        assert_eq!(sheet.shown((3, 0)), "1");
+    }
+
+    #[test]
+    fn invalid_tables_change_nothing() {
+        let mut sheet = Sheet::new();
+        sheet.enter((0, 0), "=1");
+        sheet.enter((3, 1), "2");
+        let table = Range::new((3, 0), (4, 1));
+        assert_eq!(
+            data_table(&mut sheet, table, None, None),
+            Err(WhatIfError::InvalidTable)
+        );
+        assert_eq!(
+            data_table(&mut sheet, Range::new((3, 0), (3, 1)), None, Some((1, 0))),
+            Err(WhatIfError::InvalidTable)
+        );
+        assert_eq!(
+            data_table(&mut sheet, table, None, Some((0, 0))),
+            Err(WhatIfError::InvalidInput)
+        );
+        assert!(matches!(sheet.get((4, 1)), Some(Cell::None)));
+    }
+
+    #[test]
+    fn showing_and_reverting_scenarios() {
+        let mut sheet = Sheet::new();
+        sheet.enter((0, 0), "1");
+        sheet.enter((1, 0), "2");
+        sheet.enter((2, 0), "=A1+B1");
+        add_scenario(
+            &mut sheet,
+            "high",
+            vec![((0, 0), number(10)), ((1, 0), None)],
+        )
+        .unwrap();
+        add_scenario(&mut sheet, "low", vec![((1, 0), number(-2))]).unwrap();
+
+        show_scenario(&mut sheet, "high").unwrap();
+        assert_eq!(sheet.shown((2, 0)), "12");
+        show_scenario(&mut sheet, "low").unwrap();
+        assert_eq!(sheet.shown((2, 0)), "8");
+        revert_scenario(&mut sheet).unwrap();
+        assert_eq!(sheet.shown((2, 0)), "3");
+        assert_eq!(
+            revert_scenario(&mut sheet),
+            Err(WhatIfError::NothingToRevert)
+        );
+
+        delete_scenario(&mut sheet, "low").unwrap();
+        assert_eq!(
+            show_scenario(&mut sheet, "low"),
+            Err(WhatIfError::UnknownScenario)
+        );
+    }
+
+    #[test]
+    fn an_invalid_input_shows_none_of_the_scenario() {
+        let mut sheet = Sheet::new();
+        sheet.enter((0, 0), "1");
+        sheet.enter((1, 0), "2");
+        add_scenario(
+            &mut sheet,
+            "high",
+            vec![((0, 0), number(10)), ((1, 0), number(20))],
+        )
+        .unwrap();
+        sheet.enter((1, 0), "=5");
+        assert_eq!(
+            show_scenario(&mut sheet, "high"),
+            Err(WhatIfError::InvalidInput)
+        );
+        assert_eq!(sheet.shown((0, 0)), "1");
+        assert_eq!(
+            revert_scenario(&mut sheet),
+            Err(WhatIfError::NothingToRevert)
+        );
+    }
+
+    #[test]
+    fn summaries_list_every_scenario() {
+        let mut sheet = Sheet::new();
+        sheet.enter((0, 0), "1");
+        sheet.enter((1, 0), "=A1*2");
+        add_scenario(&mut sheet, "high", vec![((0, 0), number(10))]).unwrap();
+        add_scenario(&mut sheet, "low", vec![((0, 0), number(-1))]).unwrap();
+        scenario_summary(&mut sheet, (0, 5), &[(1, 0)]).unwrap();
+
+        let row = |y| (0..3).map(|x| sheet.shown((x, y))).collect::<Vec<_>>();
+        assert!(matches!(sheet.get((0, 5)), Some(Cell::None)));
+        assert_eq!(row(5)[1..], ["high", "low"]);
+        assert_eq!(row(6), ["A1", "10", "-1"]);
+        assert_eq!(row(7), ["B1", "20", "-2"]);
+        assert_eq!(sheet.shown((1, 0)), "2");
+    }
+}
@@ -0,0 +1,117 @@
+use crossterm::style::Color;
+use crossterm_window::{
+    text::Style,
+    window::{Rect, Window},
+};
+
+use crate::model::{
+    calc::{Operand, Trace},
+    printer::describe,
+    reference::Range,
+    sheet::Sheet,
+};
+
+/// Column where the stack and the cells read are shown
+const STATE_COLUMN: u16 = 32;
+
+/// Shows a traced evaluation of a formula one instruction at a time, in
+/// place of the sheet
+#[derive(Debug)]
+pub struct DebugUi {
+    win: Window,
+}
+
+impl DebugUi {
+    pub fn new(area: Rect) -> Self {
+        DebugUi {
+            win: Window::new(area),
+        }
+    }
+
+    pub fn win(&self) -> &Window {
+        &self.win
+    }
+
+    pub fn resize(&mut self, width: u16, height: u16) {
+        self.win.resize(width, height)
+    }
+
+    /// Draws the state after `step` of the `trace` of the formula at `pos`
+    pub fn draw(&mut self, trace: &Trace, step: usize, pos: (usize, usize), sheet: &Sheet) {
+        self.win.reset();
+        let width = self.win.width() as usize;
+        let Some(current) = trace.steps.get(step) else {
+            return;
+        };
+
+        let mut formula = String::new();
+        if let Some(cell) = sheet.get(pos) {
+            cell.entry(&mut formula).unwrap();
+        }
+        let title = format!(
+            "{} {}  step {} of {}  n: next  p: previous  q: close",
+            Range::cell(pos),
+            formula,
+            step + 1,
+            trace.steps.len()
+        );
+        self.win.set_stringn(0, 0, title, width, Style::default());
+        self.win.set_style(
+            Rect::new(0, 0, self.win.width(), 1),
+            Style::default().bg(Color::Cyan).fg(Color::Black),
+        );
+
+        // Keeps the current instruction in view
+        let rows = self.win.height().saturating_sub(1) as usize;
+        let pc = current.pc.unwrap_or(trace.code.len());
+        let failed = trace.first_error().and_then(|i| trace.steps[i].pc);
+        let first = (pc + 1).saturating_sub(rows);
+        for (i, node) in trace.code.iter().enumerate().skip(first).take(rows) {
+            let y = (i - first) as u16 + 1;
+            let marker = if Some(i) == failed { '!' } else { ' ' };
+            let line = format!("{}{:>3} {}", marker, i, describe(node, pos));
+            self.win
+                .set_stringn(0, y, line, STATE_COLUMN as usize - 1, Style::default());
+            if i == pc {
+                self.win.set_style(
+                    Rect::new(0, y, STATE_COLUMN - 1, 1),
+                    Style::default().bg(Color::DarkBlue).fg(Color::White),
+                );
+            } else if Some(i) == failed {
+                self.win.set_style(
+                    Rect::new(0, y, STATE_COLUMN - 1, 1),
+                    Style::default().fg(Color::Red),
+                );
+            }
+        }
+
+        let mut lines = vec!["Stack".to_owned()];
+        lines.extend(current.stack.iter().rev().map(|op| match op {
+            Operand::Value(v) => format!("  {}", v),
+            Operand::Ref(r) => format!("  {}", r),
+        }));
+        if !current.reads.is_empty() {
+            lines.push("Read".to_owned());
+            lines.extend(current.reads.iter().map(|(p, v)| match v {
+                Some(Ok(v)) => format!("  {} = {}", Range::cell(*p), v),
+                Some(Err(e)) => format!("  {} = #Error {:?}", Range::cell(*p), e),
+                None => format!("  {} is empty", Range::cell(*p)),
+            }));
+        }
+        if let Some(e) = current.error {
+            lines.push(format!("Error {:?}", e));
+        }
+        if current.pc.is_none() {
+            lines.push(match &trace.result {
+                Ok(v) => format!("Result {}", v),
+                Err(e) => format!("Result #Error {:?}", e),
+            });
+        }
+
+        let room = width.saturating_sub(STATE_COLUMN as usize);
+        for (y, line) in lines.into_iter().take(rows).enumerate() {
+            self.win
+                .set_stringn(STATE_COLUMN, y as u16 + 1, line, room, Style::default());
+        }
+    }
+}
@@ -1,9 +1,13 @@
-use std::io;
+use std::io::{self, Write};
 
-use std::fmt::Write;
+mod debug_ui;
 mod entry_ui;
 
-use crate::model::sheet::Sheet;
+use crate::model::{
+    calc::Trace,
+    reference::column_name,
+    sheet::{Sheet, COLUMNS, ROWS},
+};
 use crossterm::{
     style::Color,
     terminal::{EnterAlternateScreen, LeaveAlternateScreen},
@@ -13,15 +17,16 @@ use crossterm_window::{
     text::Style,
     window::{Rect, Window},
 };
-use unicode_width::UnicodeWidthStr;
+use unicode_width::{UnicodeWidthChar, UnicodeWidthStr};
 
-use self::entry_ui::EntryUi;
+use self::{debug_ui::DebugUi, entry_ui::EntryUi};
 
 #[derive(Debug)]
 pub struct Ui {
     terminal: Terminal,
     sheet: SheetUi,
     entry: EntryUi,
+    debug: DebugUi,
 }
 
 impl Ui {
@@ -34,11 +39,13 @@ impl Ui {
         let term = Terminal::init();
         let sheet = SheetUi::new(Rect::new(0, 1, width, height - 2));
         let entry = EntryUi::new(Rect::new(0, 0, width, 1));
+        let debug = DebugUi::new(Rect::new(0, 1, width, height - 2));
 
         let ui = Ui {
             terminal: term,
             sheet,
             entry,
+            debug,
         };
 
         Ok(ui)
@@ -59,7 +66,8 @@ impl Ui {
     }
 
     pub fn resize(&mut self, width: u16, height: u16, sheet: &Sheet) -> io::Result<()> {
-        self.sheet.win.resize(width, height-3);
+        self.sheet.win.resize(width, height - 3);
+        self.debug.resize(width, height - 3);
         self.sheet.redraw(sheet);
         self.entry.resize(width, height);
         self.terminal.resize(width, height);
@@ -67,16 +75,46 @@ impl Ui {
         self.terminal.put(self.entry.win())
     }
 
-    pub fn set_selection(&mut self, x: usize, y: usize, sheet: &Sheet) -> io::Result<()> {
-        self.sheet.selection = (x, y);
-
+    /// Highlights cells linked to the selection, replacing the last ones
+    pub fn set_links(&mut self, cells: &[(usize, usize)], sheet: &Sheet) -> io::Result<()> {
+        self.sheet.links = cells.to_vec();
         self.sheet.draw_selection(sheet);
+        self.terminal.put(&self.sheet.win)
+    }
+
+    pub fn set_selection(&mut self, x: usize, y: usize, sheet: &Sheet) -> io::Result<()> {
+        if self.sheet.select(x, y, sheet) {
+            self.sheet.redraw(sheet);
+        } else {
+            self.sheet.draw_selection(sheet);
+        }
 
         self.terminal.put(&self.sheet.win)?;
 
         Ok(())
     }
 
+    /// Shows the state after `step` of evaluating the formula at `pos` in
+    /// place of the sheet, until the next [Ui::redraw]
+    pub fn draw_trace(
+        &mut self,
+        trace: &Trace,
+        step: usize,
+        pos: (usize, usize),
+        sheet: &Sheet,
+    ) -> io::Result<()> {
+        self.debug.draw(trace, step, pos, sheet);
+        self.terminal.put(self.debug.win())
+    }
+
+    /// Puts `text` on the system clipboard through the terminal, with the
+    /// OSC 52 escape sequence
+    pub fn set_clipboard(&mut self, text: &str) -> io::Result<()> {
+        let mut out = io::stdout();
+        write!(out, "\x1b]52;c;{}\x07", base64(text.as_bytes()))?;
+        out.flush()
+    }
+
     pub fn set_entry(&mut self, s: &str) -> io::Result<()> {
         let x = self.entry.set_text(s);
         self.terminal.put(self.entry.win())?;
@@ -88,6 +126,10 @@ impl Ui {
 struct SheetUi {
     win: Window,
     selection: (usize, usize),
+    /// The column and row shown in the top left corner
+    scroll: (usize, usize),
+    /// Cells highlighted as precedents or dependents
+    links: Vec<(usize, usize)>,
 }
 
 impl SheetUi {
@@ -95,6 +137,8 @@ impl SheetUi {
         SheetUi {
             win: Window::new(area),
             selection: (0, 0),
+            scroll: (0, 0),
+            links: vec![],
         }
     }
 
@@ -104,9 +148,72 @@ impl SheetUi {
         self.draw_selection(sheet);
     }
 
+    /// Selects `(x, y)`, scrolling just enough to show it. Returns whether
+    /// the view moved and has to be redrawn.
+    fn select(&mut self, x: usize, y: usize, sheet: &Sheet) -> bool {
+        self.selection = (x, y);
+        let before = self.scroll;
+
+        if y < self.scroll.1 {
+            self.scroll.1 = y;
+        } else if !self.shown_rows(sheet).contains(&y) {
+            // Puts `y` at the bottom, going up a screen of rows not hidden
+            let mut shown = 1;
+            self.scroll.1 = y;
+            while self.scroll.1 > 0 && shown < self.rows() {
+                self.scroll.1 -= 1;
+                shown += !sheet.is_hidden(self.scroll.1) as usize;
+            }
+        }
+
+        if x < self.scroll.0 {
+            self.scroll.0 = x;
+        }
+        while self.scroll.0 < x && self.cell_area(sheet, x, y).is_none() {
+            self.scroll.0 += 1;
+        }
+
+        self.scroll != before
+    }
+
+    /// Number of rows of cells in view
+    fn rows(&self) -> usize {
+        self.win.height().saturating_sub(1).max(1) as usize
+    }
+
+    /// The rows in view from the top, leaving out the ones hidden by the
+    /// autofilter
+    fn shown_rows(&self, sheet: &Sheet) -> Vec<usize> {
+        (self.scroll.1..ROWS)
+            .filter(|y| !sheet.is_hidden(*y))
+            .take(self.rows())
+            .collect()
+    }
+
+    /// Width of the row numbers in front of the cells
+    fn header(&self, sheet: &Sheet) -> u16 {
+        let last = self.shown_rows(sheet).last().map_or(0, |y| y + 1);
+        (last.to_string().len() as u16 + 1).max(3)
+    }
+
+    /// The visible columns with their offset in the window
+    fn columns(&self, sheet: &Sheet) -> Vec<(usize, u16)> {
+        let mut columns = vec![];
+        let mut offset = self.header(sheet);
+        for x in self.scroll.0..COLUMNS {
+            if offset >= self.win.width() {
+                break;
+            }
+            columns.push((x, offset));
+            offset += sheet.column_width(x);
+        }
+        columns
+    }
+
     fn draw_selection(&mut self, sheet: &Sheet) {
+        let header = self.header(sheet);
         self.win.set_style(
-            Rect::new(3, 1, self.win.width() - 3, self.win.height() - 1),
+            Rect::new(header, 1, self.win.width() - header, self.win.height() - 1),
             Style::reset(),
         );
         self.win.set_style(
@@ -114,86 +221,201 @@ impl SheetUi {
             Style::default().bg(Color::Cyan).fg(Color::Black),
         );
         self.win.set_style(
-            Rect::new(0, 0, 3, self.win.height()),
+            Rect::new(0, 0, header, self.win.height()),
             Style::default().bg(Color::Cyan).fg(Color::Black),
         );
 
-        let mut offset = 3;
-        for (x, row) in sheet.fields.iter().enumerate() {
-            if offset > self.win.width() {
-                break;
-            }
-
-            if self.selection.0 == x {
+        for &(x, y) in &self.links {
+            if let Some((offset, row, width)) = self.cell_area(sheet, x, y) {
                 self.win.set_style(
-                    Rect::new(offset, 0, row.0, 1),
-                    Style::default().bg(Color::DarkBlue).fg(Color::White),
+                    Rect::new(offset, row, width, 1),
+                    Style::default().bg(Color::DarkYellow).fg(Color::Black),
                 );
-                self.win.set_style(
-                    Rect::new(offset, self.selection.1 as u16 + 1, row.0, 1),
-                    Style::default().fg(Color::Black).bg(Color::Cyan),
-                );
-                break;
             }
+        }
 
-            offset += row.0;
+        let (x, y) = self.selection;
+        if let Some((offset, row, width)) = self.cell_area(sheet, x, y) {
+            self.win.set_style(
+                Rect::new(offset, 0, width, 1),
+                Style::default().bg(Color::DarkBlue).fg(Color::White),
+            );
+            self.win.set_style(
+                Rect::new(offset, row, width, 1),
+                Style::default().fg(Color::Black).bg(Color::Cyan),
+            );
+            self.win.set_style(
+                Rect::new(0, row, header, 1),
+                Style::default().bg(Color::DarkBlue).fg(Color::White),
+            );
         }
-        self.win.set_style(
-            Rect::new(0, self.selection.1 as u16 + 1, 3, 1),
-            Style::default().bg(Color::DarkBlue).fg(Color::White),
-        );
     }
 
-    fn draw_sheet(&mut self, sheet: &Sheet) {
-        let mut offset = 3;
-        for (x, row) in sheet.fields.iter().enumerate() {
-            if offset > self.win.width() {
-                break;
-            }
-
-            self.win
-                .set_stringn(offset + row.0 / 2, 0, num_to_row(x), 1, Style::default());
-
-            let mut s = "".to_string();
+    /// The column, row and width the cell is drawn at, `None` if it is out
+    /// of view
+    fn cell_area(&self, sheet: &Sheet, x: usize, y: usize) -> Option<(u16, u16, u16)> {
+        let row = self.shown_rows(sheet).iter().position(|r| *r == y)?;
+        let (_, offset) = self.columns(sheet).into_iter().find(|(c, _)| *c == x)?;
+        // Only columns shown in full count as in view
+        let width = sheet.column_width(x);
+        (offset + width <= self.win.width() || offset == self.header(sheet))
+            .then(|| (offset, row as u16 + 1, width))
+    }
 
-            for (y, cell) in row.1.iter().enumerate() {
+    fn draw_sheet(&mut self, sheet: &Sheet) {
+        let rows = self.shown_rows(sheet);
+        let mut s = "".to_string();
+        for (x, offset) in self.columns(sheet) {
+            let width = sheet.column_width(x);
+            let name = num_to_row(x);
+            self.win.set_stringn(
+                offset + width.saturating_sub(name.len() as u16) / 2,
+                0,
+                &name,
+                width as usize,
+                Style::default(),
+            );
+
+            for (i, &y) in rows.iter().enumerate() {
+                let row = i as u16 + 1;
+                let Some(cell) = sheet.get((x, y)) else {
+                    continue;
+                };
                 s.clear();
-                write!(s, "{}", cell).unwrap();
+                sheet.display((x, y), &mut s).unwrap();
                 if cell.justify_right() {
+                    let width = width as usize - 1;
+                    if UnicodeWidthStr::width(&s[..]) > width {
+                        s = abbreviate(&s, width, cell.val().is_some());
+                    }
                     let length = UnicodeWidthStr::width(&s[..]);
                     self.win.set_stringn(
-                        offset + row.0.saturating_sub(length as u16 + 1),
-                        y as u16 + 1,
+                        offset + (width as u16).saturating_sub(length as u16),
+                        row,
                         &s,
                         length,
                         Style::default(),
                     );
                 } else {
-                    self.win.set_stringn(
-                        offset,
-                        y as u16 + 1,
-                        &s,
-                        row.0 as usize - 1,
-                        Style::default(),
-                    );
+                    self.win
+                        .set_stringn(offset, row, &s, width as usize - 1, Style::default());
                 }
             }
+        }
 
-            offset += row.0;
+        let header = self.header(sheet);
+        for (i, y) in rows.into_iter().enumerate() {
+            self.win.set_stringn(
+                0,
+                i as u16 + 1,
+                format!("{}", y + 1),
+                header as usize,
+                Style::default(),
+            );
         }
+    }
+}
 
-        for i in 1..self.win.height() {
-            self.win
-                .set_stringn(0, i, format!("{}", i), 3, Style::default());
+/// Shortens a right justified value to fit in `width` columns. Integers are
+/// written in scientific notation and decimals lose their trailing digits,
+/// anything else including text computed by a formula is filled with `#`.
+fn abbreviate(s: &str, width: usize, number: bool) -> String {
+    if !number {
+        return "#".repeat(width);
+    }
+    let (sign, digits) = match s.strip_prefix('-') {
+        Some(d) => ("-", d),
+        None => ("", s),
+    };
+
+    if let Some((int, _)) = digits.split_once('.') {
+        if sign.len() + int.len() <= width && int.chars().all(|c| c.is_ascii_digit()) {
+            return truncate(s, width).trim_end_matches('.').to_string();
+        }
+    } else if !digits.is_empty() && digits.chars().all(|c| c.is_ascii_digit()) {
+        let exp = format!("e{}", digits.len() - 1);
+        let room = width.saturating_sub(sign.len() + exp.len());
+        let mantissa = match room {
+            0 => None,
+            1 | 2 => Some(digits[..1].to_string()),
+            _ => Some(format!("{}.{}", &digits[..1], &digits[1..room - 1])),
+        };
+        if let Some(m) = mantissa {
+            return format!("{}{}{}", sign, m, exp);
         }
     }
+
+    "#".repeat(width)
 }
 
-static LETTERS: [char; 26] = [
-    'A', 'B', 'C', 'D', 'E', 'F', 'G', 'H', 'I', 'J', 'K', 'L', 'M', 'N', 'O', 'P', 'Q', 'R', 'S',
-    'T', 'U', 'V', 'W', 'X', 'Y', 'Z',
-];
+/// The longest start of `s` taking at most `width` columns
+fn truncate(s: &str, width: usize) -> &str {
+    let mut taken = 0;
+    for (i, c) in s.char_indices() {
+        taken += UnicodeWidthChar::width(c).unwrap_or(0);
+        if taken > width {
+            return &s[..i];
+        }
+    }
+    s
+}
+
+fn base64(bytes: &[u8]) -> String {
+    const DIGITS: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+    let mut s = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let n = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |n, (i, b)| n | ((*b as u32) << (16 - 8 * i)));
+        for i in 0..4 {
+            match i <= chunk.len() {
+                true => s.push(DIGITS[((n >> (18 - 6 * i)) & 63) as usize] as char),
+                false => s.push('='),
+            }
+        }
+    }
+    s
+}
 
 pub fn num_to_row(row: usize) -> String {
-    LETTERS[row].to_string()
+    column_name(row)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn abbreviates_numbers() {
+        assert_eq!(abbreviate("123456789", 5, true), "1.2e8");
+        assert_eq!(abbreviate("-123456789", 4, true), "-1e8");
+        assert_eq!(abbreviate("3.14159", 4, true), "3.14");
+        assert_eq!(abbreviate("1234.5", 5, true), "1234");
+    }
+
+    #[test]
+    fn fills_anything_else() {
+        assert_eq!(abbreviate("12345", 4, false), "####");
+        // Text computed by a formula may look like a decimal
+        assert_eq!(abbreviate("1.日本日本日本", 6, false), "######");
+    }
+
+    #[test]
+    fn cuts_on_display_width() {
+        assert_eq!(truncate("1.日本", 3), "1.");
+        assert_eq!(truncate("1.日本", 4), "1.日");
+        assert_eq!(truncate("abc", 5), "abc");
+    }
+
+    #[test]
+    fn base64_with_and_without_padding() {
+        assert_eq!(base64(b""), "");
+        assert_eq!(base64(b"f"), "Zg==");
+        assert_eq!(base64(b"fo"), "Zm8=");
+        assert_eq!(base64(b"foo"), "Zm9v");
+        assert_eq!(base64(b"foob"), "Zm9vYg==");
+        assert_eq!(base64("1\t€\n".as_bytes()), "MQnigqwK");
+    }
 }